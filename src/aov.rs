@@ -0,0 +1,223 @@
+extern crate nalgebra as na;
+use na::Vector3;
+
+use std::error::Error;
+
+use crate::exr;
+use crate::film::{self, Aovs, Film};
+use crate::sampler::hash;
+use crate::scene::AovSettings;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Aov {
+    Albedo,
+    Normal,
+    Depth,
+    Position,
+    MaterialId,
+    GroupId,
+    Direct,
+    Indirect,
+    Diffuse,
+    Specular,
+}
+
+pub const ALL: [Aov; 10] = [
+    Aov::Albedo,
+    Aov::Normal,
+    Aov::Depth,
+    Aov::Position,
+    Aov::MaterialId,
+    Aov::GroupId,
+    Aov::Direct,
+    Aov::Indirect,
+    Aov::Diffuse,
+    Aov::Specular,
+];
+
+impl Aov {
+    pub fn from_name(name: &str) -> Option<Aov> {
+        ALL.iter().find(|aov| aov.name() == name).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::MaterialId => "material_id",
+            Aov::GroupId => "group_id",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Diffuse => "diffuse",
+            Aov::Specular => "specular",
+        }
+    }
+
+    fn channel_names(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::MaterialId | Aov::GroupId => &["id"],
+            _ => &["R", "G", "B"],
+        }
+    }
+
+    // Raw values of the channels, IDs are -1 where nothing was hit.
+    fn values(&self, aovs: &Aovs) -> Vec<f64> {
+        let id = |id: Option<u32>| vec![id.map_or(-1.0, |id| id as f64)];
+        let vector = |v: &Vector3<f64>| vec![v.x, v.y, v.z];
+
+        match self {
+            Aov::Albedo => vector(&aovs.albedo),
+            Aov::Normal => vector(&aovs.normal),
+            Aov::Depth => vec![aovs.depth],
+            Aov::Position => vector(&aovs.position),
+            Aov::MaterialId => id(aovs.material_id),
+            Aov::GroupId => id(aovs.group_id),
+            Aov::Direct => vector(&aovs.direct),
+            Aov::Indirect => vector(&aovs.indirect),
+            Aov::Diffuse => vector(&aovs.diffuse),
+            Aov::Specular => vector(&aovs.specular),
+        }
+    }
+
+    fn channels(&self, aovs: &[Aovs], layer: Option<&str>) -> Vec<exr::Channel> {
+        let values: Vec<Vec<f64>> = aovs.iter().map(|a| self.values(a)).collect();
+
+        self.channel_names()
+            .iter()
+            .enumerate()
+            .map(|(k, channel)| exr::Channel {
+                name: match layer {
+                    Some(layer) => format!("{}.{}", layer, channel),
+                    None => channel.to_string(),
+                },
+                values: values.iter().map(|v| v[k] as f32).collect(),
+            })
+            .collect()
+    }
+
+    // 8 bit preview: light and albedo are gamma corrected like the beauty
+    // image, normals are mapped to [0, 1], depth and position are
+    // normalized over the image and IDs get false colors.
+    fn preview(&self, film: &Film, aovs: &[Aovs]) -> image::RgbImage {
+        let colors: Vec<Vector3<f64>> = match self {
+            Aov::Normal => aovs
+                .iter()
+                .map(|a| (a.normal + Vector3::repeat(1.0)) * 0.5)
+                .collect(),
+
+            Aov::Depth => {
+                let max = aovs.iter().map(|a| a.depth).fold(0.0, f64::max).max(1.0e-9);
+                aovs.iter()
+                    .map(|a| Vector3::repeat(a.depth / max))
+                    .collect()
+            }
+
+            Aov::Position => {
+                let hits = || aovs.iter().filter(|a| a.depth > 0.0).map(|a| a.position);
+                let min = hits().fold(Vector3::repeat(f64::MAX), |a, b| a.zip_map(&b, f64::min));
+                let max = hits().fold(Vector3::repeat(f64::MIN), |a, b| a.zip_map(&b, f64::max));
+                let size = (max - min).map(|c| c.max(1.0e-9));
+
+                aovs.iter()
+                    .map(|a| {
+                        if a.depth > 0.0 {
+                            (a.position - min).component_div(&size)
+                        } else {
+                            Vector3::zeros()
+                        }
+                    })
+                    .collect()
+            }
+
+            Aov::MaterialId | Aov::GroupId => aovs
+                .iter()
+                .map(|a| {
+                    let id = if *self == Aov::MaterialId {
+                        a.material_id
+                    } else {
+                        a.group_id
+                    };
+                    id.map_or_else(Vector3::zeros, false_color)
+                })
+                .collect(),
+
+            _ => {
+                let values = aovs.iter().map(|a| {
+                    let v = self.values(a);
+                    Vector3::new(v[0], v[1], v[2])
+                });
+
+                return film::to_image(film.width, film.height, &values.collect::<Vec<_>>());
+            }
+        };
+
+        let mut im = image::RgbImage::new(film.width, film.height);
+
+        for (x, y, pixel) in im.enumerate_pixels_mut() {
+            let c = colors[(y * film.width + x) as usize];
+
+            for k in 0..3 {
+                pixel[k] = (c[k].clamp(0.0, 1.0) * 255.0) as u8;
+            }
+        }
+
+        im
+    }
+}
+
+pub fn false_color(id: u32) -> Vector3<f64> {
+    let h = hash(id.wrapping_add(1));
+
+    Vector3::new(
+        (h & 0xff) as f64 / 255.0,
+        ((h >> 8) & 0xff) as f64 / 255.0,
+        ((h >> 16) & 0xff) as f64 / 255.0,
+    )
+}
+
+// With a "{}" in the output path every AOV goes to its own file, PNG or
+// EXR by extension. Otherwise all of them are layers of one EXR file next
+// to the beauty image in the default R, G and B channels.
+pub fn write_aovs(film: &Film, settings: &AovSettings) -> Result<(), Box<dyn Error>> {
+    let aovs = film.aovs();
+
+    if settings.output.contains("{}") {
+        for aov in &settings.aovs {
+            let path = settings.output.replace("{}", aov.name());
+
+            if path.ends_with(".exr") {
+                exr::write(
+                    &path,
+                    film.width,
+                    film.height,
+                    &mut aov.channels(&aovs, None),
+                )?;
+            } else {
+                aov.preview(film, &aovs).save(&path)?;
+            }
+        }
+    } else {
+        let colors = film.colors();
+
+        let mut channels: Vec<exr::Channel> = ["R", "G", "B"]
+            .iter()
+            .enumerate()
+            .map(|(k, name)| exr::Channel {
+                name: name.to_string(),
+                values: colors.iter().map(|c| c[k] as f32).collect(),
+            })
+            .collect();
+
+        for aov in &settings.aovs {
+            channels.extend(aov.channels(&aovs, Some(aov.name())));
+        }
+
+        exr::write(&settings.output, film.width, film.height, &mut channels)?;
+    }
+
+    Ok(())
+}
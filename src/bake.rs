@@ -0,0 +1,188 @@
+extern crate nalgebra as na;
+use na::{Point3, Vector2, Vector3};
+
+use rayon::prelude::*;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Instant;
+
+use crate::integrator::unoccluded;
+use crate::mesh;
+use crate::primitive::IndexedMesh;
+use crate::ray::gamma;
+use crate::sample::{self, SampleRecord};
+use crate::scene::{BakeSettings, Scene};
+
+type Groups = Vec<(String, IndexedMesh)>;
+
+// A vertex seen like a hit, with the error bound it would have as a point on
+// one of its triangles.
+fn vertex_record(mesh: &IndexedMesh, vertex: usize, time: f64) -> SampleRecord {
+    let [x, y, z] = mesh.positions[vertex];
+    let [nx, ny, nz] = mesh.normals[vertex];
+
+    let o = Point3::new(x as f64, y as f64, z as f64);
+    let normal = Vector3::new(nx as f64, ny as f64, nz as f64).normalize();
+    let m = sample::onb(&o, &normal);
+
+    SampleRecord {
+        o,
+        error: gamma(7) * o.coords.abs(),
+        ng: normal,
+        m,
+        n: Vector3::z(),
+        v: Vector3::z(),
+        p: m * o,
+        time,
+    }
+}
+
+// See `BakeSettings`. Every vertex is a pixel to the sampler, the vertex
+// index in x and the group in y.
+pub fn bake_ao(
+    scene: &Scene,
+    settings: &BakeSettings,
+    distance: f64,
+) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    let groups = mesh::load_indexed_groups(&settings.model)?;
+    let time = scene.settings.shutter_open;
+
+    let ao: Vec<Vec<f64>> = groups
+        .iter()
+        .enumerate()
+        .map(|(group, (_, mesh))| {
+            (0..mesh.positions.len())
+                .into_par_iter()
+                .map_init(
+                    || scene.sampler.clone_box(),
+                    |sampler, vertex| {
+                        sampler.start_pixel_sample(&Vector2::new(vertex as u32, group as u32), 0);
+
+                        let s = vertex_record(mesh, vertex, time);
+                        unoccluded(&s, scene, sampler.as_mut(), distance, settings.samples)
+                    },
+                )
+                .collect()
+        })
+        .collect();
+
+    let mut w = BufWriter::new(File::create(&settings.output)?);
+
+    if settings.output.ends_with(".ply") {
+        write_ply(&mut w, &groups, &ao)?;
+    } else {
+        write_obj(&mut w, &groups, &ao)?;
+    }
+
+    w.flush()?;
+
+    println!(
+        "Baked ambient occlusion of {} vertices in {:?}",
+        ao.iter().map(|a| a.len()).sum::<usize>(),
+        start.elapsed()
+    );
+
+    Ok(())
+}
+
+// Ambient occlusion as grey vertex colors after the positions, which most
+// tools read. Groups keep their names.
+fn write_obj(w: &mut impl Write, groups: &Groups, ao: &[Vec<f64>]) -> Result<(), Box<dyn Error>> {
+    writeln!(w, "# Ambient occlusion as vertex colors")?;
+
+    let mut vertex_offset = 1;
+    let mut uv_offset = 1;
+
+    for ((name, mesh), ao) in groups.iter().zip(ao.iter()) {
+        for (p, a) in mesh.positions.iter().zip(ao.iter()) {
+            writeln!(w, "v {} {} {} {:.4} {:.4} {:.4}", p[0], p[1], p[2], a, a, a)?;
+        }
+
+        for uv in &mesh.uvs {
+            writeln!(w, "vt {} {}", uv[0], uv[1])?;
+        }
+
+        for n in &mesh.normals {
+            writeln!(w, "vn {} {} {}", n[0], n[1], n[2])?;
+        }
+
+        writeln!(w, "g {}", name)?;
+
+        for triangle in &mesh.indices {
+            write!(w, "f")?;
+
+            for &i in triangle {
+                let v = vertex_offset + i as usize;
+
+                if mesh.uvs.is_empty() {
+                    write!(w, " {}//{}", v, v)?;
+                } else {
+                    write!(w, " {}/{}/{}", v, uv_offset + i as usize, v)?;
+                }
+            }
+
+            writeln!(w)?;
+        }
+
+        vertex_offset += mesh.positions.len();
+        uv_offset += mesh.uvs.len();
+    }
+
+    Ok(())
+}
+
+// ASCII PLY of all groups together, with the ambient occlusion both as a
+// float property and as grey vertex colors.
+fn write_ply(w: &mut impl Write, groups: &Groups, ao: &[Vec<f64>]) -> Result<(), Box<dyn Error>> {
+    let vertices: usize = groups.iter().map(|(_, mesh)| mesh.positions.len()).sum();
+    let triangles: usize = groups.iter().map(|(_, mesh)| mesh.indices.len()).sum();
+
+    writeln!(w, "ply")?;
+    writeln!(w, "format ascii 1.0")?;
+    writeln!(w, "element vertex {}", vertices)?;
+
+    for property in &["x", "y", "z", "nx", "ny", "nz", "ao"] {
+        writeln!(w, "property float {}", property)?;
+    }
+
+    for property in &["red", "green", "blue"] {
+        writeln!(w, "property uchar {}", property)?;
+    }
+
+    writeln!(w, "element face {}", triangles)?;
+    writeln!(w, "property list uchar uint vertex_indices")?;
+    writeln!(w, "end_header")?;
+
+    for ((_, mesh), ao) in groups.iter().zip(ao.iter()) {
+        for ((p, n), a) in mesh.positions.iter().zip(&mesh.normals).zip(ao) {
+            let grey = (a.clamp(0.0, 1.0) * 255.0).round() as u8;
+
+            writeln!(
+                w,
+                "{} {} {} {} {} {} {:.4} {} {} {}",
+                p[0], p[1], p[2], n[0], n[1], n[2], a, grey, grey, grey
+            )?;
+        }
+    }
+
+    let mut offset = 0;
+
+    for (_, mesh) in groups {
+        for triangle in &mesh.indices {
+            writeln!(
+                w,
+                "3 {} {} {}",
+                offset + triangle[0],
+                offset + triangle[1],
+                offset + triangle[2]
+            )?;
+        }
+
+        offset += mesh.positions.len() as u32;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,777 @@
+extern crate nalgebra as na;
+use na::{Isometry3, Point3, Vector2, Vector3};
+
+use crate::brdf::{BRDFInput, BRDF};
+use crate::film::{Aovs, FilmTile};
+use crate::integrator::reached_light;
+use crate::light::Light;
+use crate::object::IntersectionRecord;
+use crate::ray::Ray;
+use crate::sample;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::stats;
+
+// Bidirectional path tracing (Veach 1997) in the formulation of pbrt-v3.
+// Camera and light subpaths are connected at every pair of vertices and
+// weighted with the balance heuristic. Lights aren't part of the geometry,
+// so camera paths only find them along the reflections of delta BRDFs,
+// which can't be connected to, and only lights start light paths.
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Camera,
+    Light,
+    Surface,
+}
+
+// What a subpath carries: radiance from the lights along camera paths, or
+// importance from the camera along light paths.
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Radiance,
+    Importance,
+}
+
+// `pdf_fwd` is the area density of the vertex as sampled by its subpath,
+// `pdf_rev` the one it would have if sampled from the other end. Surface
+// normals are flipped to the side the vertex was reached from, and `m`
+// takes world space to their shading frame.
+#[derive(Clone)]
+struct Vertex<'a> {
+    kind: Kind,
+    p: Point3<f64>,
+    error: Vector3<f64>,
+    ng: Vector3<f64>,
+    n: Vector3<f64>,
+    m: Isometry3<f64>,
+    wo: Vector3<f64>,
+    brdf: Option<&'a dyn BRDF>,
+    light: Option<&'a dyn Light>,
+    beta: Vector3<f64>,
+    delta: bool,
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    time: f64,
+}
+
+impl<'a> Vertex<'a> {
+    fn camera(p: Point3<f64>, beta: Vector3<f64>, delta: bool, time: f64) -> Vertex<'a> {
+        Vertex {
+            kind: Kind::Camera,
+            p,
+            error: Vector3::zeros(),
+            ng: Vector3::zeros(),
+            n: Vector3::zeros(),
+            m: Isometry3::identity(),
+            wo: Vector3::zeros(),
+            brdf: None,
+            light: None,
+            beta,
+            delta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            time,
+        }
+    }
+
+    fn light(
+        light: &'a dyn Light,
+        p: Point3<f64>,
+        beta: Vector3<f64>,
+        pdf_fwd: f64,
+        time: f64,
+    ) -> Vertex<'a> {
+        let n = light.normal(&p);
+
+        Vertex {
+            kind: Kind::Light,
+            p,
+            error: Vector3::zeros(),
+            ng: n,
+            n,
+            m: sample::onb(&p, &n),
+            wo: Vector3::zeros(),
+            brdf: None,
+            light: Some(light),
+            beta,
+            delta: false,
+            pdf_fwd,
+            pdf_rev: 0.0,
+            time,
+        }
+    }
+
+    fn surface(ray: &Ray, record: &IntersectionRecord<'a>, beta: Vector3<f64>) -> Vertex<'a> {
+        let wo = -ray.direction.normalize();
+
+        let side = if record.geometric_normal.dot(&wo) < 0.0 {
+            -1.0
+        } else {
+            1.0
+        };
+
+        let n = side * record.normal;
+
+        Vertex {
+            kind: Kind::Surface,
+            p: record.point,
+            error: record.error,
+            ng: side * record.geometric_normal,
+            n,
+            m: sample::onb(&record.point, &n),
+            wo,
+            brdf: Some(record.brdf),
+            light: None,
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            time: ray.time,
+        }
+    }
+
+    fn on_surface(&self) -> bool {
+        self.kind != Kind::Camera
+    }
+
+    // Whether a path can be joined at this vertex.
+    fn connectible(&self) -> bool {
+        match self.brdf {
+            Some(brdf) => !brdf.delta(),
+            None => true,
+        }
+    }
+
+    // Solid angle density at this vertex turned into area density at `next`.
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        let w = next.p - self.p;
+        let d2 = w.norm_squared();
+
+        if d2 == 0.0 {
+            return 0.0;
+        }
+
+        if next.on_surface() {
+            pdf * next.n.dot(&(w / d2.sqrt())).abs() / d2
+        } else {
+            pdf / d2
+        }
+    }
+
+    // Area density of light paths starting at this light vertex.
+    fn pdf_light_origin(&self, scene: &Scene, next: &Vertex) -> f64 {
+        let w = (next.p - self.p).normalize();
+        let (pdf_pos, _) = self.light.unwrap().emission_pdf(&self.p, &w);
+
+        pdf_pos / scene.lights.len() as f64
+    }
+
+    // Area density of sampling `next` from this vertex, reached from `prev`.
+    fn pdf(&self, scene: &Scene, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        let w = (next.p - self.p).normalize();
+
+        let pdf = match self.kind {
+            Kind::Camera => scene.camera.pdf(&w, self.time).unwrap_or(0.0),
+            Kind::Light => self.light.unwrap().emission_pdf(&self.p, &w).1,
+            Kind::Surface => {
+                let wp = (prev.unwrap().p - self.p).normalize();
+                self.brdf.unwrap().pdf(&(self.m * wp), &(self.m * w))
+            }
+        };
+
+        self.convert_density(pdf, next)
+    }
+
+    // Adjoint BRDFs with shading normals aren't symmetric, see Veach 5.3.
+    fn shading_correction(&self, wi: &Vector3<f64>) -> f64 {
+        let den = self.wo.dot(&self.ng).abs() * wi.dot(&self.n).abs();
+
+        if den == 0.0 {
+            0.0
+        } else {
+            self.wo.dot(&self.n).abs() * wi.dot(&self.ng).abs() / den
+        }
+    }
+
+    // BRDF of scattering between the previous vertex and `next`. Light only
+    // leaves on the side it arrives from.
+    fn f(&self, next: &Vertex, mode: Mode) -> Vector3<f64> {
+        let brdf = match self.brdf {
+            Some(brdf) if !brdf.delta() => brdf,
+            _ => return Vector3::zeros(),
+        };
+
+        let wi = (next.p - self.p).normalize();
+
+        if wi.dot(&self.ng) <= 0.0 {
+            return Vector3::zeros();
+        }
+
+        let (v, l) = match mode {
+            Mode::Radiance => (self.m * self.wo, self.m * wi),
+            Mode::Importance => (self.m * wi, self.m * self.wo),
+        };
+
+        if v.z <= 0.0 || l.z <= 0.0 {
+            return Vector3::zeros();
+        }
+
+        let f = brdf.f(&BRDFInput::new(&Vector3::z(), &l, &v));
+
+        match mode {
+            Mode::Radiance => f,
+            Mode::Importance => f * self.shading_correction(&wi),
+        }
+    }
+}
+
+fn visible(scene: &Scene, a: &Vertex, b: &Vertex) -> bool {
+    let mut ray = Ray::spawn(&a.p, &a.error, &a.ng, &(b.p - a.p), a.time);
+    let target = Ray::spawn(&b.p, &b.error, &b.ng, &(a.p - b.p), b.time).origin;
+
+    let d = target - ray.origin;
+    ray.direction = d.normalize();
+
+    stats::count(|c| c.shadow_rays += 1);
+
+    !scene.obj.occluded(&ray, d.norm())
+}
+
+// Geometry term between two vertices, zero if they don't see each other.
+fn g(scene: &Scene, a: &Vertex, b: &Vertex) -> f64 {
+    let w = b.p - a.p;
+    let d2 = w.norm_squared();
+    let w = w / d2.sqrt();
+
+    let mut g = 1.0 / d2;
+
+    if a.on_surface() {
+        g *= a.n.dot(&w).abs();
+    }
+
+    if b.on_surface() {
+        g *= b.n.dot(&w).abs();
+    }
+
+    if g > 0.0 && visible(scene, a, b) {
+        g
+    } else {
+        0.0
+    }
+}
+
+// Extends `path` from its last vertex along `ray` up to `max_vertices`.
+// `pdf` is the solid angle density `ray` was sampled with. The first hit
+// seen from the camera fills `first_hit`. Lights reached right after delta
+// vertices go to `lights`, each with the length of the path ending on it.
+#[allow(clippy::too_many_arguments)]
+fn random_walk<'a>(
+    scene: &'a Scene,
+    mut ray: Ray,
+    sampler: &mut dyn Sampler,
+    mut beta: Vector3<f64>,
+    mut pdf: f64,
+    max_vertices: usize,
+    mode: Mode,
+    path: &mut Vec<Vertex<'a>>,
+    mut first_hit: Option<&mut Aovs>,
+    mut lights: Option<&mut Vec<(usize, Vertex<'a>)>>,
+) {
+    while path.len() < max_vertices {
+        // The camera ray is counted as a primary ray by the renderer.
+        if mode == Mode::Importance || path.len() > 1 {
+            stats::count(|c| c.secondary_rays += 1);
+        }
+
+        let record = match scene.obj.intersect(&ray) {
+            Some(record) => record,
+            None => break,
+        };
+
+        let mut vertex = Vertex::surface(&ray, &record, beta);
+        let prev = path.last().unwrap();
+        vertex.pdf_fwd = prev.convert_density(pdf, &vertex);
+
+        let brdf = record.brdf;
+
+        if let Some(aovs) = first_hit.take() {
+            *aovs = Aovs {
+                albedo: brdf.albedo(),
+                normal: record.normal,
+                depth: record.t,
+                position: record.point.coords,
+                material_id: Some(record.material_id),
+                group_id: Some(record.group_id),
+                ..Aovs::new()
+            };
+        }
+
+        // Delta vertices are followed to the light they reflect even when
+        // they end the path.
+        let reflects_lights = lights.is_some() && brdf.delta();
+        let last = path.len() + 1 == max_vertices;
+
+        if last && !reflects_lights {
+            path.push(vertex);
+            break;
+        }
+
+        let wo = vertex.m * vertex.wo;
+        let (l, l_pdf) = brdf.p(&wo, &sampler.get_2d());
+        let wi = vertex.m.inverse_transform_vector(&l);
+
+        let f = match mode {
+            Mode::Radiance => brdf.f(&BRDFInput::new(&Vector3::z(), &l, &wo)),
+            Mode::Importance => brdf.f(&BRDFInput::new(&Vector3::z(), &wo, &l)),
+        };
+
+        vertex.delta = brdf.delta();
+
+        let mut weight = if vertex.delta {
+            f
+        } else if l_pdf > 0.0 {
+            f * l.z.abs() / l_pdf
+        } else {
+            Vector3::zeros()
+        };
+
+        if mode == Mode::Importance {
+            weight *= vertex.shading_correction(&wi);
+        }
+
+        let (pdf_fwd, pdf_rev) = if vertex.delta {
+            (0.0, 0.0)
+        } else {
+            (l_pdf, brdf.pdf(&l, &wo))
+        };
+
+        let prev = path.last_mut().unwrap();
+        prev.pdf_rev = vertex.convert_density(pdf_rev, prev);
+
+        // Rough BRDFs don't scatter below the geometric surface, see `f`.
+        // Delta BRDFs follow the shading normal like the path tracer does.
+        let below = !vertex.delta && wi.dot(&vertex.ng) <= 0.0;
+        let stop = weight == Vector3::zeros() || below;
+
+        ray = Ray::spawn(&vertex.p, &vertex.error, &vertex.ng, &wi, vertex.time);
+        beta = beta.component_mul(&weight);
+        pdf = pdf_fwd;
+
+        if let Some(lights) = lights.as_deref_mut().filter(|_| reflects_lights) {
+            if let Some((light, p)) = reached_light(scene, &ray) {
+                let mut reached = Vertex::light(light, p, beta, 0.0, vertex.time);
+                reached.pdf_fwd = vertex.convert_density(pdf, &reached);
+
+                lights.push((path.len() + 2, reached));
+            }
+        }
+
+        path.push(vertex);
+
+        if stop || last {
+            break;
+        }
+    }
+}
+
+// See `random_walk` for `lights`.
+fn camera_path<'a>(
+    scene: &'a Scene,
+    ray: Ray,
+    sampler: &mut dyn Sampler,
+    max_vertices: usize,
+    aovs: &mut Aovs,
+    lights: &mut Vec<(usize, Vertex<'a>)>,
+) -> Vec<Vertex<'a>> {
+    let pdf = scene.camera.pdf(&ray.direction, ray.time);
+
+    // Cameras without a pdf can't be connected to, which the MIS weights
+    // see as a delta vertex.
+    let mut path = vec![Vertex::camera(
+        ray.origin,
+        Vector3::repeat(1.0),
+        pdf.is_none(),
+        ray.time,
+    )];
+
+    random_walk(
+        scene,
+        ray,
+        sampler,
+        Vector3::repeat(1.0),
+        pdf.unwrap_or(1.0),
+        max_vertices,
+        Mode::Radiance,
+        &mut path,
+        Some(aovs),
+        Some(lights),
+    );
+
+    path
+}
+
+fn light_path<'a>(
+    scene: &'a Scene,
+    time: f64,
+    sampler: &mut dyn Sampler,
+    max_vertices: usize,
+) -> Vec<Vertex<'a>> {
+    if scene.lights.is_empty() {
+        return vec![];
+    }
+
+    let light_pdf = 1.0 / scene.lights.len() as f64;
+    let light = scene.get_light(sampler.get_1d());
+
+    let e = light.sample_emission(&sampler.get_2d(), &sampler.get_2d());
+
+    if e.pdf_pos == 0.0 || e.pdf_dir == 0.0 {
+        return vec![];
+    }
+
+    let mut path = vec![Vertex::light(
+        light,
+        e.point,
+        e.radiance,
+        e.pdf_pos * light_pdf,
+        time,
+    )];
+
+    let beta = e.radiance * e.normal.dot(&e.direction).abs() / (light_pdf * e.pdf_pos * e.pdf_dir);
+
+    random_walk(
+        scene,
+        Ray::new(e.point, e.direction, time),
+        sampler,
+        beta,
+        e.pdf_dir,
+        max_vertices,
+        Mode::Importance,
+        &mut path,
+        None,
+        None,
+    );
+
+    path
+}
+
+// Balance heuristic weight of the path made of the first `s` light and `t`
+// camera vertices, where `sampled` replaces the light vertex if s = 1 or the
+// camera vertex if t = 1. Paths with s = 0 end on a light reached after a
+// delta vertex, which only light paths of at least two vertices can find
+// too, or on an emissive surface, which no other strategy can find.
+fn mis_weight(
+    scene: &Scene,
+    light: &[Vertex],
+    camera: &[Vertex],
+    sampled: Option<&Vertex>,
+    s: usize,
+    t: usize,
+) -> f64 {
+    if s == 0 && camera[t - 1].light.is_none() {
+        return 1.0;
+    }
+
+    let qs = match s {
+        0 => None,
+        1 => sampled,
+        _ => Some(&light[s - 1]),
+    };
+    let pt = if t == 1 {
+        sampled.unwrap()
+    } else {
+        &camera[t - 1]
+    };
+
+    // Densities and delta flags as the connected path sees them.
+    let entry = |v: &Vertex| (v.pdf_fwd, v.pdf_rev, v.delta);
+
+    let mut light_pdfs: Vec<_> = light[..s].iter().map(entry).collect();
+    let mut camera_pdfs: Vec<_> = camera[..t].iter().map(entry).collect();
+
+    camera_pdfs[t - 1] = (pt.pdf_fwd, 0.0, false);
+
+    let qs_prev = if s > 1 { Some(&light[s - 2]) } else { None };
+    let pt_prev = if t > 1 { Some(&camera[t - 2]) } else { None };
+
+    camera_pdfs[t - 1].1 = match qs {
+        Some(qs) => qs.pdf(scene, qs_prev, pt),
+        None => pt.pdf_light_origin(scene, pt_prev.unwrap()),
+    };
+
+    if let Some(pt_prev) = pt_prev {
+        camera_pdfs[t - 2].1 = pt.pdf(scene, qs, pt_prev);
+    }
+
+    if let Some(qs) = qs {
+        light_pdfs[s - 1] = (qs.pdf_fwd, pt.pdf(scene, pt_prev, qs), false);
+
+        if let Some(qs_prev) = qs_prev {
+            light_pdfs[s - 2].1 = qs.pdf(scene, Some(pt), qs_prev);
+        }
+    }
+
+    let remap = |pdf: f64| if pdf != 0.0 { pdf } else { 1.0 };
+
+    let mut sum = 0.0;
+
+    let mut ri = 1.0;
+    for i in (1..t).rev() {
+        ri *= remap(camera_pdfs[i].1) / remap(camera_pdfs[i].0);
+
+        if !camera_pdfs[i].2 && !camera_pdfs[i - 1].2 {
+            sum += ri;
+        }
+    }
+
+    // Camera paths only hit the light the light path starts on when the
+    // next vertex is a delta vertex, which is the strategy with s = 0.
+    let mut ri = 1.0;
+    for i in (0..s).rev() {
+        ri *= remap(light_pdfs[i].1) / remap(light_pdfs[i].0);
+
+        let found = if i > 0 {
+            !light_pdfs[i - 1].2
+        } else {
+            s > 1 && light_pdfs[1].2
+        };
+
+        if !light_pdfs[i].2 && found {
+            sum += ri;
+        }
+    }
+
+    1.0 / (1.0 + sum)
+}
+
+// Weighted light of joining the first `s` light and `t` camera vertices.
+// `raster` is where it lands on the film if t = 1, `source` the light side
+// end of the connection.
+struct Connection {
+    l: Vector3<f64>,
+    raster: Option<Vector2<f64>>,
+    source: Point3<f64>,
+}
+
+fn connect(
+    scene: &Scene,
+    light: &[Vertex],
+    camera: &[Vertex],
+    s: usize,
+    t: usize,
+    sampler: &mut dyn Sampler,
+) -> Option<Connection> {
+    let mut sampled = None;
+    let mut raster = None;
+
+    let l = if s == 0 {
+        let pt = &camera[t - 1];
+
+        match (pt.brdf, pt.light) {
+            (Some(brdf), _) => pt.beta.component_mul(&brdf.e()),
+            (None, Some(light)) => {
+                let le = light.radiance(&pt.p, &(camera[t - 2].p - pt.p));
+                pt.beta.component_mul(&le)
+            }
+            (None, None) => return None,
+        }
+    } else if t == 1 {
+        let qs = &light[s - 1];
+
+        if !qs.connectible() {
+            return None;
+        }
+
+        let importance = scene.camera.sample_importance(&qs.p, qs.time)?;
+
+        let vertex = Vertex::camera(
+            importance.origin,
+            Vector3::repeat(importance.importance / importance.pdf),
+            false,
+            qs.time,
+        );
+
+        let mut l = qs
+            .beta
+            .component_mul(&qs.f(&vertex, Mode::Importance))
+            .component_mul(&vertex.beta);
+
+        if qs.on_surface() {
+            l *= qs.n.dot(&(vertex.p - qs.p).normalize()).abs();
+        }
+
+        if l == Vector3::zeros() || !visible(scene, qs, &vertex) {
+            return None;
+        }
+
+        raster = Some(importance.raster);
+        sampled = Some(vertex);
+        l
+    } else if s == 1 {
+        let pt = &camera[t - 1];
+
+        if !pt.connectible() {
+            return None;
+        }
+
+        let light_pdf = 1.0 / scene.lights.len() as f64;
+        let light = scene.get_light(sampler.get_1d());
+        let (p, pdf) = light.sample_point(&sampler.get_2d());
+
+        if pdf == 0.0 {
+            return None;
+        }
+
+        let le = light.radiance(&p, &(pt.p - p));
+        let vertex = Vertex::light(light, p, le / (pdf * light_pdf), pdf * light_pdf, pt.time);
+
+        let l = pt
+            .beta
+            .component_mul(&pt.f(&vertex, Mode::Radiance))
+            .component_mul(&vertex.beta);
+
+        if l == Vector3::zeros() {
+            return None;
+        }
+
+        sampled = Some(vertex);
+        l * g(scene, pt, sampled.as_ref().unwrap())
+    } else {
+        let qs = &light[s - 1];
+        let pt = &camera[t - 1];
+
+        if !qs.connectible() || !pt.connectible() {
+            return None;
+        }
+
+        let l = qs
+            .beta
+            .component_mul(&qs.f(pt, Mode::Importance))
+            .component_mul(&pt.f(qs, Mode::Radiance))
+            .component_mul(&pt.beta);
+
+        if l == Vector3::zeros() {
+            return None;
+        }
+
+        l * g(scene, qs, pt)
+    };
+
+    if l == Vector3::zeros() {
+        return None;
+    }
+
+    let source = match (&sampled, s) {
+        (_, 0) => camera[t - 1].p,
+        (Some(vertex), 1) => vertex.p,
+        _ => light[s - 1].p,
+    };
+
+    Some(Connection {
+        l: l * mis_weight(scene, light, camera, sampled.as_ref(), s, t),
+        raster,
+        source,
+    })
+}
+
+// Share of the light leaving the first hit towards the camera that comes
+// through its diffuse lobe, for light arriving from `p`.
+fn diffuse_share(v: &Vertex, p: &Point3<f64>) -> Vector3<f64> {
+    let brdf = v.brdf.unwrap();
+
+    let wo = v.m * v.wo;
+    let l = v.m * (p - v.p).normalize();
+    let n = Vector3::z();
+    let input = BRDFInput::new(&n, &l, &wo);
+
+    let f = brdf.f(&input);
+    let (fd, _) = brdf.lobes(&input);
+
+    Vector3::from_fn(|k, _| if f[k] > 0.0 { fd[k] / f[k] } else { 0.0 })
+}
+
+// Adds light that reached the camera along the first `t` vertices of
+// `camera` to the AOVs.
+fn add_aovs(aovs: &mut Aovs, camera: &[Vertex], c: &Connection, s: usize, t: usize) {
+    if s + t == 3 || (s == 0 && t == 2) {
+        aovs.direct += c.l;
+    } else {
+        aovs.indirect += c.l;
+    }
+
+    // Emission of the first hit belongs to neither lobe.
+    if s == 0 && t == 2 {
+        return;
+    }
+
+    // The light reaches the first hit from the next vertex of the camera
+    // path, or from the light side if the path ends there.
+    let next = if t > 2 { camera[2].p } else { c.source };
+
+    let diffuse = c.l.component_mul(&diffuse_share(&camera[1], &next));
+
+    aovs.diffuse += diffuse;
+    aovs.specular += c.l - diffuse;
+}
+
+// Light arriving along the camera `ray` through paths of up to `depth`
+// bounces. Light tracing contributions (t = 1) land on other pixels and are
+// splatted to `tile` instead. The AOVs split the light like the path
+// tracer, except for the splats which they don't see.
+pub fn radiance(
+    depth: i32,
+    ray: Ray,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+    aovs: &mut Aovs,
+    tile: &mut FilmTile,
+) -> Vector3<f64> {
+    let depth = depth.max(0) as usize;
+    let time = ray.time;
+
+    let mut reached = vec![];
+    let camera = camera_path(scene, ray, sampler, depth + 1, aovs, &mut reached);
+    let light = light_path(scene, time, sampler, depth + 1);
+
+    let mut color = Vector3::zeros();
+
+    for t in 1..=camera.len() {
+        for s in 0..=light.len() {
+            // Lights can't be seen directly, and s = 0 needs an emissive
+            // surface to end on. Lights reached by the camera path are
+            // handled below.
+            if s + t < 2 || s + t > depth + 2 || (s == 1 && t == 1) {
+                continue;
+            }
+
+            let c = match connect(scene, &light, &camera, s, t, sampler) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            if let Some(p) = c.raster {
+                tile.add_splat(&p, &c.l);
+                continue;
+            }
+
+            color += c.l;
+            add_aovs(aovs, &camera, &c, s, t);
+        }
+    }
+
+    // Camera paths ending on the lights reached after their delta vertices.
+    for (t, vertex) in reached {
+        let mut path = camera[..t - 1].to_vec();
+        path.push(vertex);
+
+        if let Some(c) = connect(scene, &light, &path, 0, t, sampler) {
+            color += c.l;
+            add_aovs(aovs, &path, &c, 0, t);
+        }
+    }
+
+    stats::count(|c| c.path_vertices += (camera.len() - 1 + light.len()) as u64);
+
+    color
+}
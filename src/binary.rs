@@ -0,0 +1,39 @@
+extern crate nalgebra as na;
+use na::Vector3;
+
+use std::error::Error;
+use std::io::{Read, Write};
+
+// Little endian helpers shared by the binary file formats.
+
+pub fn write_vector(w: &mut impl Write, v: &Vector3<f64>) -> Result<(), Box<dyn Error>> {
+    for c in v.iter() {
+        w.write_all(&c.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+pub fn read_u32(r: &mut impl Read) -> Result<u32, Box<dyn Error>> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+pub fn read_f32(r: &mut impl Read) -> Result<f32, Box<dyn Error>> {
+    Ok(f32::from_bits(read_u32(r)?))
+}
+
+pub fn read_u64(r: &mut impl Read) -> Result<u64, Box<dyn Error>> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+pub fn read_f64(r: &mut impl Read) -> Result<f64, Box<dyn Error>> {
+    Ok(f64::from_bits(read_u64(r)?))
+}
+
+pub fn read_vector(r: &mut impl Read) -> Result<Vector3<f64>, Box<dyn Error>> {
+    Ok(Vector3::new(read_f64(r)?, read_f64(r)?, read_f64(r)?))
+}
@@ -1,14 +1,32 @@
 extern crate nalgebra as na;
-use na::Vector3;
+use na::{Vector2, Vector3};
 
 use crate::sample;
 
 use std::f64::consts::PI;
 
-pub trait BRDF {
+pub trait BRDF: Send + Sync {
     fn f(&self, input: &BRDFInput) -> Vector3<f64>;
-    fn p(&self, v: &Vector3<f64>) -> (Vector3<f64>, f64);
+    fn p(&self, v: &Vector3<f64>, u: &Vector2<f64>) -> (Vector3<f64>, f64);
     fn e(&self) -> Vector3<f64>;
+
+    // Overall reflectance, used as a feature to guide the denoiser.
+    fn albedo(&self) -> Vector3<f64>;
+
+    // Diffuse and specular parts of `f`, they sum up to `f`.
+    fn lobes(&self, input: &BRDFInput) -> (Vector3<f64>, Vector3<f64>);
+
+    // Glossy stand-in for BRDFs sharper than `roughness`, None if the BRDF
+    // is rough enough already.
+    fn roughened(&self, roughness: f64) -> Option<Box<dyn BRDF>>;
+
+    // Solid angle pdf of `p` sampling `l` from `v`, zero for delta BRDFs.
+    fn pdf(&self, v: &Vector3<f64>, l: &Vector3<f64>) -> f64;
+
+    // Whether `p` only ever returns one direction. `f` is then the
+    // reflectance along it rather than a density, and the pdf is
+    // meaningless.
+    fn delta(&self) -> bool;
 }
 
 pub struct BRDFInput<'a> {
@@ -33,12 +51,32 @@ impl BRDF for EmissiveBRDF {
     fn f(&self, _: &BRDFInput) -> Vector3<f64> {
         Vector3::zeros()
     }
-    fn p(&self, _: &Vector3<f64>) -> (Vector3<f64>, f64) {
+    fn p(&self, _: &Vector3<f64>, _: &Vector2<f64>) -> (Vector3<f64>, f64) {
         (Vector3::zeros(), 0.0)
     }
     fn e(&self) -> Vector3<f64> {
         self.color * self.power
     }
+
+    fn albedo(&self) -> Vector3<f64> {
+        self.color
+    }
+
+    fn lobes(&self, _: &BRDFInput) -> (Vector3<f64>, Vector3<f64>) {
+        (Vector3::zeros(), Vector3::zeros())
+    }
+
+    fn roughened(&self, _: f64) -> Option<Box<dyn BRDF>> {
+        None
+    }
+
+    fn pdf(&self, _: &Vector3<f64>, _: &Vector3<f64>) -> f64 {
+        0.0
+    }
+
+    fn delta(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Clone)]
@@ -48,16 +86,37 @@ pub struct DiffuseBRDF {
 
 impl BRDF for DiffuseBRDF {
     fn f(&self, _: &BRDFInput) -> Vector3<f64> {
-        self.color
+        self.color / PI
     }
 
-    fn p(&self, _: &Vector3<f64>) -> (Vector3<f64>, f64) {
-        (sample::uniform_hemisphere(), 1.0 / 2.0 * PI)
+    fn p(&self, v: &Vector3<f64>, u: &Vector2<f64>) -> (Vector3<f64>, f64) {
+        let l = sample::uniform_hemisphere(u);
+        (l, self.pdf(v, &l))
     }
 
     fn e(&self) -> Vector3<f64> {
         Vector3::zeros()
     }
+
+    fn albedo(&self) -> Vector3<f64> {
+        self.color
+    }
+
+    fn lobes(&self, input: &BRDFInput) -> (Vector3<f64>, Vector3<f64>) {
+        (self.f(input), Vector3::zeros())
+    }
+
+    fn roughened(&self, _: f64) -> Option<Box<dyn BRDF>> {
+        None
+    }
+
+    fn pdf(&self, _: &Vector3<f64>, l: &Vector3<f64>) -> f64 {
+        uniform_hemisphere_pdf(l)
+    }
+
+    fn delta(&self) -> bool {
+        false
+    }
 }
 
 pub struct MirrorBRDF {
@@ -69,13 +128,38 @@ impl BRDF for MirrorBRDF {
         self.color
     }
 
-    fn p(&self, v: &Vector3<f64>) -> (Vector3<f64>, f64) {
-        (sample::reflect_onb(&v), 1.0)
+    fn p(&self, v: &Vector3<f64>, _: &Vector2<f64>) -> (Vector3<f64>, f64) {
+        (sample::reflect_onb(v), 1.0)
     }
 
     fn e(&self) -> Vector3<f64> {
         Vector3::zeros()
     }
+
+    fn albedo(&self) -> Vector3<f64> {
+        self.color
+    }
+
+    fn lobes(&self, input: &BRDFInput) -> (Vector3<f64>, Vector3<f64>) {
+        (Vector3::zeros(), self.f(input))
+    }
+
+    fn roughened(&self, roughness: f64) -> Option<Box<dyn BRDF>> {
+        Some(Box::new(MicrofacetBRDF {
+            albedo: Vector3::zeros(),
+            f0: self.color,
+            roughness,
+            specular: 1.0,
+        }))
+    }
+
+    fn pdf(&self, _: &Vector3<f64>, _: &Vector3<f64>) -> f64 {
+        0.0
+    }
+
+    fn delta(&self) -> bool {
+        true
+    }
 }
 
 #[derive(Clone)]
@@ -90,28 +174,63 @@ impl BRDF for MicrofacetBRDF {
     fn f(&self, input: &BRDFInput) -> Vector3<f64> {
         let h = (input.l + input.v).normalize();
 
-        let num = ggx_ndf(self.roughness, &input.n, &h)
-            * ggx_g1(self.roughness, &input.n, &input.v)
-            * ggx_g1(self.roughness, &input.n, &input.l)
-            * fresnel_schlick(&self.f0, &input.n, &input.l);
+        let num = ggx_ndf(self.roughness, input.n, &h)
+            * ggx_g1(self.roughness, input.n, input.v)
+            * ggx_g1(self.roughness, input.n, input.l)
+            * fresnel_schlick(&self.f0, input.n, input.l);
 
-        let den = 4.0 * (input.n.dot(&input.l) * input.n.dot(&input.v));
+        let den = 4.0 * (input.n.dot(input.l) * input.n.dot(input.v));
 
         let s = num / den;
 
         (self.albedo / PI) + (s * self.specular)
     }
 
-    fn p(&self, _: &Vector3<f64>) -> (Vector3<f64>, f64) {
-        (
-            sample::uniform_hemisphere(),
-            1.0 / 2.0 * std::f64::consts::PI,
-        )
+    fn p(&self, v: &Vector3<f64>, u: &Vector2<f64>) -> (Vector3<f64>, f64) {
+        let l = sample::uniform_hemisphere(u);
+        (l, self.pdf(v, &l))
     }
 
     fn e(&self) -> Vector3<f64> {
         Vector3::zeros()
     }
+
+    fn albedo(&self) -> Vector3<f64> {
+        (self.albedo + self.f0 * self.specular).map(|c| c.min(1.0))
+    }
+
+    fn lobes(&self, input: &BRDFInput) -> (Vector3<f64>, Vector3<f64>) {
+        let diffuse = self.albedo / PI;
+
+        (diffuse, self.f(input) - diffuse)
+    }
+
+    fn roughened(&self, roughness: f64) -> Option<Box<dyn BRDF>> {
+        if self.roughness < roughness {
+            Some(Box::new(MicrofacetBRDF {
+                roughness,
+                ..self.clone()
+            }))
+        } else {
+            None
+        }
+    }
+
+    fn pdf(&self, _: &Vector3<f64>, l: &Vector3<f64>) -> f64 {
+        uniform_hemisphere_pdf(l)
+    }
+
+    fn delta(&self) -> bool {
+        false
+    }
+}
+
+fn uniform_hemisphere_pdf(l: &Vector3<f64>) -> f64 {
+    if l.z > 0.0 {
+        1.0 / (2.0 * PI)
+    } else {
+        0.0
+    }
 }
 
 fn ggx_chi(a: f64) -> f64 {
@@ -124,24 +243,24 @@ fn ggx_chi(a: f64) -> f64 {
 
 fn ggx_ndf(alpha: f64, n: &Vector3<f64>, m: &Vector3<f64>) -> f64 {
     let alpha2 = alpha * alpha;
-    let dot = n.dot(&m);
+    let dot = n.dot(m);
     let denom = 1.0 + dot * dot * (alpha2 - 1.0);
     (ggx_chi(dot) * alpha2) / (PI * denom * denom)
 }
 
 fn ggx_g1(alpha: f64, n: &Vector3<f64>, s: &Vector3<f64>) -> f64 {
-    let dot = n.dot(&s);
+    let dot = n.dot(s);
     (2.0 * dot * ggx_chi(dot)) / ((2.0 - alpha) + alpha)
 }
 
 fn fresnel_schlick_scalar(f0: f64, n: &Vector3<f64>, l: &Vector3<f64>) -> f64 {
-    f0 + (1.0 - f0) * (1.0 - n.dot(&l)).powf(5.0)
+    f0 + (1.0 - f0) * (1.0 - n.dot(l)).powf(5.0)
 }
 
 fn fresnel_schlick(f0: &Vector3<f64>, n: &Vector3<f64>, l: &Vector3<f64>) -> Vector3<f64> {
     Vector3::<f64>::new(
-        fresnel_schlick_scalar(f0[0], &n, &l),
-        fresnel_schlick_scalar(f0[1], &n, &l),
-        fresnel_schlick_scalar(f0[2], &n, &l),
+        fresnel_schlick_scalar(f0[0], n, l),
+        fresnel_schlick_scalar(f0[1], n, l),
+        fresnel_schlick_scalar(f0[2], n, l),
     )
 }
@@ -1,18 +1,30 @@
 extern crate nalgebra as na;
-use na::Vector3;
+use na::{Isometry3, Point3, Vector3};
 
-use crate::primitive::{AggregatePrimitive, IntersectionRecord, Primitive, Triangle};
+use crate::binary::{read_f32, read_f64, read_u32, read_vector, write_vector};
+use crate::object::{self, Intersect};
+use crate::primitive::{
+    AggregatePrimitive, Bounded, IndexedMesh, IntersectionRecord, MeshTriangle, Primitive, Shear,
+};
 
 use crate::ray::Ray;
 
+use crate::sampler::{hash, hash_combine};
+use crate::stats;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 #[derive(Clone)]
-struct Bounds {
-    max: Vector3<f64>,
-    min: Vector3<f64>,
+pub struct Bounds {
+    pub max: Vector3<f64>,
+    pub min: Vector3<f64>,
 }
 
 impl Bounds {
-    fn intersect(&self, ray: &Ray) -> bool {
+    pub fn intersect(&self, ray: &Ray) -> bool {
         let inv = Vector3::repeat(1.000001).component_div(&ray.direction);
 
         let t0 = (self.min - ray.origin.coords).component_mul(&inv);
@@ -27,6 +39,88 @@ impl Bounds {
 
         tmin < tmax
     }
+
+    // Distance at which `ray` enters the box, if it does so in
+    // `[ray.t_min, t_max]`. `inv` is the component wise inverse of the ray
+    // direction.
+    pub fn entry(&self, ray: &Ray, inv: &Vector3<f64>, t_max: f64) -> Option<f64> {
+        let t0 = (self.min - ray.origin.coords).component_mul(inv);
+        let t1 = (self.max - ray.origin.coords).component_mul(inv);
+
+        let tmin =
+            t0.x.min(t1.x)
+                .max(t0.y.min(t1.y))
+                .max(t0.z.min(t1.z))
+                .max(ray.t_min);
+        let tmax =
+            t0.x.max(t1.x)
+                .min(t0.y.max(t1.y))
+                .min(t0.z.max(t1.z))
+                .min(t_max);
+
+        if tmin <= tmax {
+            Some(tmin)
+        } else {
+            None
+        }
+    }
+
+    pub fn union(&self, b: &Bounds) -> Bounds {
+        Bounds {
+            min: Vector3::new(
+                self.min.x.min(b.min.x),
+                self.min.y.min(b.min.y),
+                self.min.z.min(b.min.z),
+            ),
+            max: Vector3::new(
+                self.max.x.max(b.max.x),
+                self.max.y.max(b.max.y),
+                self.max.z.max(b.max.z),
+            ),
+        }
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = (self.max - self.min).map(|c| c.max(0.0));
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    pub fn centroid(&self) -> Vector3<f64> {
+        (self.max + self.min) / 2.0
+    }
+
+    pub fn corners(&self) -> Vec<Point3<f64>> {
+        (0..8)
+            .map(|i| {
+                Point3::new(
+                    if i & 1 == 0 { self.min.x } else { self.max.x },
+                    if i & 2 == 0 { self.min.y } else { self.max.y },
+                    if i & 4 == 0 { self.min.z } else { self.max.z },
+                )
+            })
+            .collect()
+    }
+
+    pub fn transform(&self, m: &Isometry3<f64>) -> Bounds {
+        let corners: Vec<Point3<f64>> = self.corners().iter().map(|c| m * c).collect();
+        Bounds::around(&corners)
+    }
+
+    pub fn around(points: &[Point3<f64>]) -> Bounds {
+        let mut bounds = Bounds {
+            min: points[0].coords,
+            max: points[0].coords,
+        };
+
+        for p in points[1..].iter() {
+            bounds = bounds.union(&Bounds {
+                min: p.coords,
+                max: p.coords,
+            });
+        }
+
+        bounds
+    }
 }
 
 enum Node {
@@ -35,114 +129,836 @@ enum Node {
 }
 
 impl Node {
-    fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord> {
+    fn depth(&self) -> usize {
         match self {
-            Node::Internal(node) => {
-                let left_hit = if node.left_bounds.intersect(&ray) {
-                    node.left.intersect(&ray)
-                } else {
-                    None
-                };
+            Node::Internal(node) => 1 + node.left.depth().max(node.right.depth()),
+            Node::Leaf(_) => 1,
+        }
+    }
+}
 
-                let right_hit = if node.right_bounds.intersect(&ray) {
-                    node.right.intersect(&ray)
-                } else {
-                    None
-                };
+// Nodes are stored depth first, so the left child of an internal node
+// directly follows it and only the right child needs an index. The items,
+// triangles or objects, of a leaf are the `count` items starting at
+// `offset`.
+pub(crate) struct LinearNode {
+    pub(crate) bounds: Bounds,
+    pub(crate) offset: u32,
+    pub(crate) count: u32,
+}
 
-                if left_hit.is_some() && right_hit.is_some() {
-                    let l = left_hit.unwrap();
-                    let r = right_hit.unwrap();
+// Deeper trees are rebuilt with the median builder so the traversal stack
+// can live on the stack.
+pub(crate) const MAX_DEPTH: usize = 64;
 
-                    if l.t < r.t {
-                        Some(l)
-                    } else {
-                        Some(r)
-                    }
-                } else {
-                    if left_hit.is_some() {
-                        left_hit
-                    } else {
-                        right_hit
-                    }
-                }
+// Nodes with fewer references build their children on the current thread.
+const PARALLEL_THRESHOLD: usize = 4096;
+
+// Primitives without bounds, like planes, are tested one after another
+// after the tree.
+//
+// `order` holds the index in the mesh of each stored primitive, so updated
+// primitives can be put in place. `build_cost` is the SAH cost right after
+// building, the reference for the quality of refitted trees.
+pub struct Tree<T: Primitive + Bounded> {
+    pub(crate) nodes: Vec<LinearNode>,
+    pub(crate) primitives: Vec<T>,
+    order: Vec<u32>,
+    pub unbounded: Vec<Box<dyn Primitive>>,
+    build_cost: f64,
+    build_time: Duration,
+    cached: bool,
+}
+
+impl<T: Primitive + Bounded> Tree<T> {
+    pub fn new(mesh: AggregatePrimitive<T>) -> Tree<T> {
+        Tree::with_settings(mesh, &BuildSettings::default())
+    }
+
+    pub fn with_settings(mesh: AggregatePrimitive<T>, settings: &BuildSettings) -> Tree<T> {
+        let start = Instant::now();
+
+        let bounds = mesh.primitives.iter().map(|p| p.aabb()).collect();
+        let (nodes, order) = build(bounds, settings);
+
+        let mut tree = Tree {
+            nodes,
+            primitives: reorder(mesh.primitives, &order),
+            order: order.iter().map(|i| *i as u32).collect(),
+            unbounded: vec![],
+            build_cost: 0.0,
+            build_time: start.elapsed(),
+            cached: false,
+        };
+
+        tree.build_cost = tree.sah_cost(settings);
+        tree
+    }
+
+    // Replaces the primitives with `mesh`, the same primitives in the same
+    // order as when building but moved, and refits the tree to them. Once
+    // the SAH cost has grown by more than `settings.refit_threshold` times
+    // the tree is rebuilt instead. Returns whether it was rebuilt.
+    pub fn update(
+        &mut self,
+        mesh: AggregatePrimitive<T>,
+        settings: &BuildSettings,
+    ) -> Result<bool, Box<dyn Error>> {
+        if mesh.primitives.len() != self.primitives.len() {
+            return Err(format!(
+                "Updated mesh has {} primitives instead of {}",
+                mesh.primitives.len(),
+                self.primitives.len()
+            )
+            .into());
+        }
+
+        let order: Vec<usize> = self.order.iter().map(|i| *i as usize).collect();
+        self.primitives = reorder(mesh.primitives, &order);
+        self.refit();
+
+        match settings.refit_threshold {
+            Some(threshold) if self.sah_cost(settings) > self.build_cost * threshold => {
+                self.rebuild(settings);
+                Ok(true)
             }
+            _ => Ok(false),
+        }
+    }
 
-            Node::Leaf(leaf) => {
-                let mut closest: Option<IntersectionRecord> = None;
+    // Recomputes the bounds of all nodes bottom up, keeping the topology.
+    pub fn refit(&mut self) {
+        let primitives = &self.primitives;
 
-                for tri_ref in leaf.refs.iter() {
-                    if let Some(record) = tri_ref.tri_ref.intersect(ray) {
-                        closest = match closest {
-                            Some(old_record) if old_record.t > record.t => Some(record),
-                            Some(_) => closest,
-                            None => Some(record),
-                        };
-                    }
-                }
+        refit(&mut self.nodes, |start, end| {
+            primitives[start..end]
+                .iter()
+                .map(|p| p.aabb())
+                .reduce(|a, b| a.union(&b))
+                .unwrap()
+        });
+    }
+
+    fn rebuild(&mut self, settings: &BuildSettings) {
+        let primitives = std::mem::take(&mut self.primitives);
+        let mut tree = Tree::with_settings(AggregatePrimitive { primitives }, settings);
+
+        // The new order is relative to the old one.
+        tree.order = tree.order.iter().map(|i| self.order[*i as usize]).collect();
+        tree.unbounded = std::mem::take(&mut self.unbounded);
+
+        *self = tree;
+    }
+
+    pub fn stats(&self) -> BuildStats {
+        let mut depths = vec![];
+
+        if !self.nodes.is_empty() {
+            leaf_depths(&self.nodes, 0, 0, &mut depths);
+        }
+
+        BuildStats {
+            time: self.build_time,
+            cached: self.cached,
+            nodes: self.nodes.len(),
+            leaves: self.nodes.iter().filter(|n| n.count > 0).count(),
+            depths,
+            memory: self.nodes.len() * size_of::<LinearNode>()
+                + self.primitives.len() * size_of::<T>()
+                + self.unbounded.len() * size_of::<Box<dyn Primitive>>(),
+        }
+    }
+
+    fn hit(&self, i: usize, ray: &Ray, shear: &Shear) -> Option<(f64, IntersectionRecord)> {
+        self.primitives[i]
+            .intersect_sheared(ray, shear)
+            .map(|r| (r.t, r))
+    }
+
+    pub fn sah_cost(&self, settings: &BuildSettings) -> f64 {
+        if self.nodes.is_empty() {
+            return 0.0;
+        }
+
+        self.node_sah_cost(0, self.nodes[0].bounds.surface_area(), settings)
+    }
+
+    // Expected cost of a ray hitting node `index`, relative to the area of
+    // the root bounds `root_area`.
+    fn node_sah_cost(&self, index: usize, root_area: f64, settings: &BuildSettings) -> f64 {
+        let node = &self.nodes[index];
+        let p = node.bounds.surface_area() / root_area;
+
+        if node.count > 0 {
+            p * settings.intersection_cost * node.count as f64
+        } else {
+            p * settings.traversal_cost
+                + self.node_sah_cost(index + 1, root_area, settings)
+                + self.node_sah_cost(node.offset as usize, root_area, settings)
+        }
+    }
+
+    // Average number of nodes visited by rays from a sphere around the
+    // tree towards random points inside of its bounds.
+    pub fn average_visits(&self, rays: u32) -> f64 {
+        let bounds = &self.nodes[0].bounds;
+
+        let total: usize = (0..rays)
+            .map(|i| {
+                let ray = probe_ray(bounds, i);
+                let shear = Shear::new(&ray.direction);
+
+                let mut visits = 0;
+                traverse(&self.nodes, &ray, false, &mut visits, |i, ray| {
+                    self.hit(i, ray, &shear)
+                });
+
+                visits
+            })
+            .sum();
+
+        total as f64 / rays as f64
+    }
+}
+
+// Build cost, then nodes as min, max, offset and count, then the indexed
+// mesh as vertex positions and normals, texture coordinates (none or one
+// per vertex) and triangle vertex indices, then the primitives as mesh
+// index and triangle index. All primitives have to share one mesh. See
+// `bvh_cache` for the file around it.
+impl Tree<MeshTriangle> {
+    // Moves the triangles to the vertices of `mesh`, which has to have the
+    // same triangles as the current mesh. See `update`.
+    pub fn update_mesh(
+        &mut self,
+        mesh: IndexedMesh,
+        settings: &BuildSettings,
+    ) -> Result<bool, Box<dyn Error>> {
+        let same = match self.primitives.first() {
+            Some(p) => p.mesh.indices == mesh.indices && p.mesh.uvs.len() == mesh.uvs.len(),
+            None => mesh.indices.is_empty(),
+        };
+
+        if !same {
+            return Err("Updated mesh has different triangles".into());
+        }
+
+        let mesh = Arc::new(mesh);
+
+        let primitives = (0..mesh.indices.len() as u32)
+            .map(|index| MeshTriangle {
+                mesh: mesh.clone(),
+                index,
+            })
+            .collect();
+
+        self.update(AggregatePrimitive { primitives }, settings)
+    }
+
+    pub fn write(&self, w: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        w.write_all(&self.build_cost.to_le_bytes())?;
+        w.write_all(&(self.nodes.len() as u32).to_le_bytes())?;
+
+        for node in &self.nodes {
+            write_vector(w, &node.bounds.min)?;
+            write_vector(w, &node.bounds.max)?;
+            w.write_all(&node.offset.to_le_bytes())?;
+            w.write_all(&node.count.to_le_bytes())?;
+        }
+
+        let empty = IndexedMesh {
+            positions: vec![],
+            normals: vec![],
+            uvs: vec![],
+            indices: vec![],
+        };
+        let mesh = self.primitives.first().map_or(&empty, |p| &p.mesh);
+
+        debug_assert!(self
+            .primitives
+            .iter()
+            .all(|p| Arc::ptr_eq(&p.mesh, &self.primitives[0].mesh)));
+
+        w.write_all(&(mesh.positions.len() as u32).to_le_bytes())?;
+
+        for (pos, nrm) in mesh.positions.iter().zip(mesh.normals.iter()) {
+            for c in pos.iter().chain(nrm.iter()) {
+                w.write_all(&c.to_le_bytes())?;
+            }
+        }
+
+        w.write_all(&(mesh.uvs.len() as u32).to_le_bytes())?;
 
-                closest
+        for c in mesh.uvs.iter().flatten() {
+            w.write_all(&c.to_le_bytes())?;
+        }
+
+        w.write_all(&(mesh.indices.len() as u32).to_le_bytes())?;
+
+        for triangle in &mesh.indices {
+            for i in triangle {
+                w.write_all(&i.to_le_bytes())?;
             }
         }
+
+        w.write_all(&(self.primitives.len() as u32).to_le_bytes())?;
+
+        for (tri, index) in self.primitives.iter().zip(self.order.iter()) {
+            w.write_all(&index.to_le_bytes())?;
+            w.write_all(&tri.index.to_le_bytes())?;
+        }
+
+        Ok(())
     }
 
-    fn intersect_debug(&self, ray: &Ray) -> Vector3<f64> {
-        match self {
-            Node::Internal(node) => {
-                let mut res: Vector3<f64> = Vector3::repeat(0.0);
+    pub fn read(r: &mut impl Read) -> Result<Tree<MeshTriangle>, Box<dyn Error>> {
+        let start = Instant::now();
+        let build_cost = read_f64(r)?;
 
-                if node.left_bounds.intersect(&ray) {
-                    res += Vector3::new(0.0, 0.0, 0.01) + node.left.intersect_debug(&ray);
-                };
+        let mut nodes = vec![];
+        for _ in 0..read_u32(r)? {
+            nodes.push(LinearNode {
+                bounds: Bounds {
+                    min: read_vector(r)?,
+                    max: read_vector(r)?,
+                },
+                offset: read_u32(r)?,
+                count: read_u32(r)?,
+            });
+        }
 
-                if node.right_bounds.intersect(&ray) {
-                    res += Vector3::new(0.0, 0.0, 0.01) + node.right.intersect_debug(&ray);
-                };
+        let mut mesh = IndexedMesh {
+            positions: vec![],
+            normals: vec![],
+            uvs: vec![],
+            indices: vec![],
+        };
+
+        let vertices = read_u32(r)?;
+        for _ in 0..vertices {
+            mesh.positions
+                .push([read_f32(r)?, read_f32(r)?, read_f32(r)?]);
+            mesh.normals
+                .push([read_f32(r)?, read_f32(r)?, read_f32(r)?]);
+        }
 
-                res
+        let uvs = read_u32(r)?;
+        if uvs != 0 && uvs != vertices {
+            return Err("Invalid BVH mesh".into());
+        }
+
+        for _ in 0..uvs {
+            mesh.uvs.push([read_f32(r)?, read_f32(r)?]);
+        }
+
+        for _ in 0..read_u32(r)? {
+            let triangle = [read_u32(r)?, read_u32(r)?, read_u32(r)?];
+
+            if triangle.iter().any(|i| *i >= vertices) {
+                return Err("Invalid BVH mesh".into());
             }
 
-            Node::Leaf(leaf) => {
-                let mut res: Vector3<f64> = Vector3::repeat(0.0);
+            mesh.indices.push(triangle);
+        }
+
+        let mesh = Arc::new(mesh);
 
-                for tri_ref in leaf.refs.iter() {
-                    if let Some(_) = tri_ref.tri_ref.intersect(ray) {
-                        res += Vector3::new(0.1, 0.0, 0.0);
-                    }
+        let mut primitives = vec![];
+        let mut order = vec![];
+        for _ in 0..read_u32(r)? {
+            order.push(read_u32(r)?);
 
-                    if tri_ref.bounds.intersect(ray) {
-                        res += Vector3::new(0.0, 0.1, 0.0);
-                    }
+            let index = read_u32(r)?;
+
+            if index as usize >= mesh.indices.len() {
+                return Err("Invalid BVH mesh".into());
+            }
+
+            primitives.push(MeshTriangle {
+                mesh: mesh.clone(),
+                index,
+            });
+        }
+
+        // Indices are checked once here instead of on every traversal. Depths
+        // count nodes from the root like `Node::depth`, children follow their
+        // parents so the depth of a parent is final when it is reached.
+        let mut valid = true;
+        let mut depths = vec![1; nodes.len()];
+
+        for (i, node) in nodes.iter().enumerate() {
+            if node.count > 0 {
+                valid &= node.offset as usize + node.count as usize <= primitives.len();
+            } else if node.offset as usize > i + 1 && (node.offset as usize) < nodes.len() {
+                for child in [i + 1, node.offset as usize] {
+                    depths[child] = depths[child].max(depths[i] + 1);
                 }
+            } else {
+                valid = false;
+            }
+        }
+
+        let valid = valid && order.iter().all(|i| (*i as usize) < primitives.len());
+
+        if !valid || (nodes.is_empty() != primitives.is_empty()) {
+            return Err("Invalid BVH nodes".into());
+        }
+
+        // The traversal stack only fits trees as deep as built ones.
+        if depths.iter().any(|d| *d > MAX_DEPTH) {
+            return Err("BVH deeper than the traversal stack".into());
+        }
+
+        Ok(Tree {
+            nodes,
+            primitives,
+            order,
+            unbounded: vec![],
+            build_cost,
+            build_time: start.elapsed(),
+            cached: true,
+        })
+    }
+}
+
+impl<T: Primitive + Bounded> Primitive for Tree<T> {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord> {
+        let shear = Shear::new(&ray.direction);
+
+        let (mut visits, mut tests) = (0, self.unbounded.len());
+        let mut closest = traverse(&self.nodes, ray, false, &mut visits, |i, ray| {
+            tests += 1;
+            self.hit(i, ray, &shear)
+        });
+
+        stats::count_traversal(visits, tests);
 
-                res
+        for primitive in self.unbounded.iter() {
+            let ray = Ray {
+                t_max: closest.as_ref().map_or(ray.t_max, |c| c.t),
+                ..*ray
+            };
+
+            if let Some(record) = primitive.intersect(&ray) {
+                if closest.as_ref().is_none_or(|c| record.t < c.t) {
+                    closest = Some(record);
+                }
             }
         }
+
+        closest
+    }
+
+    fn occluded(&self, ray: &Ray, t_max: f64) -> bool {
+        let ray = Ray {
+            t_max: t_max.min(ray.t_max),
+            ..*ray
+        };
+
+        if self.unbounded.iter().any(|p| p.occluded(&ray, ray.t_max)) {
+            return true;
+        }
+
+        let shear = Shear::new(&ray.direction);
+
+        let (mut visits, mut tests) = (0, self.unbounded.len());
+        let hit = traverse(&self.nodes, &ray, true, &mut visits, |i, ray| {
+            tests += 1;
+            self.hit(i, ray, &shear)
+        });
+
+        stats::count_traversal(visits, tests);
+
+        hit.is_some()
+    }
+
+    fn bounds(&self) -> Option<Bounds> {
+        if self.unbounded.is_empty() {
+            self.nodes.first().map(|n| n.bounds.clone())
+        } else {
+            None
+        }
     }
 }
 
-pub struct Tree {
-    root: Node,
+// `depths` counts the leaves at each depth, the root being at depth 0.
+// `memory` covers the nodes and primitives, not what primitives point to.
+// `time` is the time it took to load the tree if it was `cached`.
+pub struct BuildStats {
+    pub time: Duration,
+    pub cached: bool,
+    pub nodes: usize,
+    pub leaves: usize,
+    pub depths: Vec<usize>,
+    pub memory: usize,
 }
 
-impl Tree {
-    pub fn new(mesh: AggregatePrimitive<Triangle>) -> Tree {
-        Tree {
-            root: build_node(build_refs(mesh)),
+impl BuildStats {
+    pub fn print(&self, name: &str) {
+        println!(
+            "{}: {} in {:.2?}, {} nodes, {} leaves, {:.2} MiB",
+            name,
+            if self.cached {
+                "loaded from cache"
+            } else {
+                "built"
+            },
+            self.time,
+            self.nodes,
+            self.leaves,
+            self.memory as f64 / (1024.0 * 1024.0)
+        );
+
+        let max = self.depths.iter().copied().max().unwrap_or(0).max(1);
+
+        for (depth, count) in self.depths.iter().enumerate() {
+            if *count > 0 {
+                println!(
+                    "  depth {:>2}: {:>8} {}",
+                    depth,
+                    count,
+                    "#".repeat((count * 40).div_ceil(max))
+                );
+            }
         }
     }
+}
+
+fn leaf_depths(nodes: &[LinearNode], index: usize, depth: usize, depths: &mut Vec<usize>) {
+    let node = &nodes[index];
+
+    if node.count > 0 {
+        if depths.len() <= depth {
+            depths.resize(depth + 1, 0);
+        }
 
-    pub fn debug(&self, ray: &Ray) -> Vector3<f64> {
-        self.root.intersect_debug(ray)
+        depths[depth] += 1;
+    } else {
+        leaf_depths(nodes, index + 1, depth + 1, depths);
+        leaf_depths(nodes, node.offset as usize, depth + 1, depths);
     }
 }
 
-impl Primitive for Tree {
-    fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord> {
-        self.root.intersect(ray)
+// Top level hierarchy over objects. Objects without bounds are tested one
+// after another after the hierarchy.
+pub struct ObjectTree {
+    nodes: Vec<LinearNode>,
+    objects: Vec<Box<dyn Intersect>>,
+    unbounded: Vec<Box<dyn Intersect>>,
+}
+
+impl ObjectTree {
+    pub fn new(objects: Vec<Box<dyn Intersect>>, settings: &BuildSettings) -> ObjectTree {
+        let (bounded, unbounded): (Vec<_>, Vec<_>) =
+            objects.into_iter().partition(|o| o.bounds().is_some());
+
+        let bounds = bounded.iter().map(|o| o.bounds().unwrap()).collect();
+        let (nodes, order) = build(bounds, settings);
+
+        ObjectTree {
+            nodes,
+            objects: reorder(bounded, &order),
+            unbounded,
+        }
     }
 }
 
+impl Intersect for ObjectTree {
+    fn intersect(&self, ray: &Ray) -> Option<object::IntersectionRecord<'_>> {
+        let mut visits = 0;
+        let mut closest = traverse(&self.nodes, ray, false, &mut visits, |i, ray| {
+            self.objects[i].intersect(ray).map(|r| (r.t, r))
+        });
+
+        stats::count_traversal(visits, 0);
+
+        for object in self.unbounded.iter() {
+            let ray = Ray {
+                t_max: closest.as_ref().map_or(ray.t_max, |c| c.t),
+                ..*ray
+            };
+
+            if let Some(record) = object.intersect(&ray) {
+                if closest.as_ref().is_none_or(|c| record.t < c.t) {
+                    closest = Some(record);
+                }
+            }
+        }
+
+        closest
+    }
+
+    fn occluded(&self, ray: &Ray, t_max: f64) -> bool {
+        let ray = Ray {
+            t_max: t_max.min(ray.t_max),
+            ..*ray
+        };
+
+        // The distance doesn't matter, an any hit traversal stops at the
+        // first hit.
+        if self.unbounded.iter().any(|o| o.occluded(&ray, ray.t_max)) {
+            return true;
+        }
+
+        let mut visits = 0;
+        let hit = traverse(&self.nodes, &ray, true, &mut visits, |i, ray| {
+            if self.objects[i].occluded(ray, ray.t_max) {
+                Some((ray.t_max, ()))
+            } else {
+                None
+            }
+        });
+
+        stats::count_traversal(visits, 0);
+
+        hit.is_some()
+    }
+
+    fn bounds(&self) -> Option<Bounds> {
+        if self.unbounded.is_empty() {
+            self.nodes.first().map(|n| n.bounds.clone())
+        } else {
+            None
+        }
+    }
+
+    // Objects keep their place in the tree, only the bounds are refitted.
+    fn set_frame(&mut self, frame: usize) -> Result<(), Box<dyn Error>> {
+        if !self.animated() {
+            return Ok(());
+        }
+
+        for object in self.objects.iter_mut().chain(self.unbounded.iter_mut()) {
+            object.set_frame(frame)?;
+        }
+
+        let objects = &self.objects;
+
+        refit(&mut self.nodes, |start, end| {
+            objects[start..end]
+                .iter()
+                .map(|o| o.bounds().unwrap())
+                .reduce(|a, b| a.union(&b))
+                .unwrap()
+        });
+
+        Ok(())
+    }
+
+    fn animated(&self) -> bool {
+        self.objects
+            .iter()
+            .chain(self.unbounded.iter())
+            .any(|o| o.animated())
+    }
+}
+
+// Builds the linear nodes over items with the given bounds, returns them
+// along with the order the items have to be stored in.
+fn build(bounds: Vec<Bounds>, settings: &BuildSettings) -> (Vec<LinearNode>, Vec<usize>) {
+    if bounds.is_empty() {
+        return (vec![], vec![]);
+    }
+
+    let start = Instant::now();
+
+    let mut refs: Vec<BuildRef> = bounds
+        .into_iter()
+        .enumerate()
+        .map(|(index, bounds)| BuildRef {
+            centroid: bounds.centroid(),
+            bounds,
+            index,
+        })
+        .collect();
+
+    let root_bounds = refs_bounds(&refs);
+
+    let mut root = match settings.builder {
+        Builder::Median => build_node(&mut refs),
+        Builder::Sah => build_node_sah(&mut refs, settings),
+    };
+
+    if root.depth() > MAX_DEPTH {
+        root = build_node(&mut refs);
+    }
+
+    let mut nodes = vec![];
+    flatten(root, root_bounds, &mut nodes, &mut 0);
+
+    stats::add_build_time(start.elapsed());
+
+    // Leaves own consecutive ranges of the partitioned references.
+    (nodes, refs.iter().map(|r| r.index).collect())
+}
+
+fn reorder<T>(items: Vec<T>, order: &[usize]) -> Vec<T> {
+    let mut items: Vec<Option<T>> = items.into_iter().map(Some).collect();
+    order.iter().map(|i| items[*i].take().unwrap()).collect()
+}
+
+// Recomputes the bounds of all nodes bottom up, keeping the topology.
+// `leaf_bounds` gives the bounds of the items from `start` to `end`.
+// Children are stored after their parents, so a reverse pass sees them
+// first.
+fn refit(nodes: &mut [LinearNode], leaf_bounds: impl Fn(usize, usize) -> Bounds) {
+    for index in (0..nodes.len()).rev() {
+        let node = &nodes[index];
+
+        let bounds = if node.count > 0 {
+            let start = node.offset as usize;
+            leaf_bounds(start, start + node.count as usize)
+        } else {
+            nodes[index + 1]
+                .bounds
+                .union(&nodes[node.offset as usize].bounds)
+        };
+
+        nodes[index].bounds = bounds;
+    }
+}
+
+fn flatten(node: Node, bounds: Bounds, nodes: &mut Vec<LinearNode>, offset: &mut u32) {
+    let index = nodes.len();
+
+    match node {
+        Node::Internal(node) => {
+            nodes.push(LinearNode {
+                bounds,
+                offset: 0,
+                count: 0,
+            });
+
+            flatten(*node.left, node.left_bounds, nodes, offset);
+            nodes[index].offset = nodes.len() as u32;
+            flatten(*node.right, node.right_bounds, nodes, offset);
+        }
+
+        Node::Leaf(leaf) => {
+            nodes.push(LinearNode {
+                bounds,
+                offset: *offset,
+                count: leaf.count as u32,
+            });
+
+            *offset += leaf.count as u32;
+        }
+    }
+}
+
+// Ray number `i` from a sphere around `bounds` towards a random point
+// inside of them, for measuring traversal.
+pub(crate) fn probe_ray(bounds: &Bounds, i: u32) -> Ray {
+    let center = bounds.centroid();
+    let radius = (bounds.max - bounds.min).norm();
+
+    let u = |d: u32| hash_combine(hash(i), d) as f64 / 4_294_967_296.0;
+
+    let z = 1.0 - 2.0 * u(0);
+    let phi = 2.0 * std::f64::consts::PI * u(1);
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let origin = center + Vector3::new(r * phi.cos(), r * phi.sin(), z) * radius;
+
+    let target =
+        bounds.min + (bounds.max - bounds.min).component_mul(&Vector3::new(u(2), u(3), u(4)));
+
+    Ray::new(origin.into(), (target - origin).normalize(), 0.0)
+}
+
+// Iterative traversal visiting the nearer child first and skipping nodes
+// entered beyond the closest hit so far. `hit` intersects item `i` with a
+// ray clipped to the closest hit, returning the distance and record. With
+// `any_hit` the first hit found is returned. `visits` counts the nodes
+// entered.
+fn traverse<R>(
+    nodes: &[LinearNode],
+    ray: &Ray,
+    any_hit: bool,
+    visits: &mut usize,
+    mut hit: impl FnMut(usize, &Ray) -> Option<(f64, R)>,
+) -> Option<R> {
+    if nodes.is_empty() {
+        return None;
+    }
+
+    let inv = Vector3::repeat(1.0).component_div(&ray.direction);
+
+    let mut closest: Option<R> = None;
+    let mut clipped = *ray;
+
+    let mut stack = [(0, 0.0); MAX_DEPTH + 1];
+    let mut len = 0;
+
+    if let Some(t) = nodes[0].bounds.entry(ray, &inv, clipped.t_max) {
+        stack[0] = (0, t);
+        len = 1;
+    }
+
+    while len > 0 {
+        len -= 1;
+        let (index, entry) = stack[len];
+
+        if entry > clipped.t_max {
+            continue;
+        }
+
+        *visits += 1;
+        let node = &nodes[index];
+
+        if node.count > 0 {
+            for i in node.offset..node.offset + node.count {
+                if let Some((t, record)) = hit(i as usize, &clipped) {
+                    if any_hit {
+                        return Some(record);
+                    }
+
+                    if t < clipped.t_max || closest.is_none() {
+                        clipped.t_max = t;
+                        closest = Some(record);
+                    }
+                }
+            }
+
+            continue;
+        }
+
+        let left = index + 1;
+        let right = node.offset as usize;
+
+        let left_t = nodes[left].bounds.entry(ray, &inv, clipped.t_max);
+        let right_t = nodes[right].bounds.entry(ray, &inv, clipped.t_max);
+
+        // The nearer child is pushed last so it is popped first.
+        match (left_t, right_t) {
+            (Some(l), Some(r)) => {
+                let (near, far) = if l <= r {
+                    ((left, l), (right, r))
+                } else {
+                    ((right, r), (left, l))
+                };
+
+                stack[len] = far;
+                stack[len + 1] = near;
+                len += 2;
+            }
+
+            (Some(l), None) => {
+                stack[len] = (left, l);
+                len += 1;
+            }
+
+            (None, Some(r)) => {
+                stack[len] = (right, r);
+                len += 1;
+            }
+
+            (None, None) => {}
+        }
+    }
+
+    closest
+}
+
 struct InternalNode {
     left_bounds: Bounds,
     right_bounds: Bounds,
@@ -150,14 +966,17 @@ struct InternalNode {
     right: Box<Node>,
 }
 
-#[derive(Clone)]
-struct TriangleRef {
+// Items are referenced by their index while building.
+struct BuildRef {
     bounds: Bounds,
-    tri_ref: Triangle,
+    centroid: Vector3<f64>,
+    index: usize,
 }
 
+// The references of a node are partitioned in place, so a leaf only needs
+// to know how many it got.
 struct LeafNode {
-    refs: Vec<TriangleRef>,
+    count: usize,
 }
 
 #[derive(Clone, Copy)]
@@ -167,40 +986,7 @@ enum SortAxis {
     Z = 2,
 }
 
-fn triangle_bounds(tri: &Triangle) -> Bounds {
-    Bounds {
-        max: Vector3::new(
-            tri.vert[0]
-                .pos
-                .x
-                .max(tri.vert[1].pos.x.max(tri.vert[2].pos.x)),
-            tri.vert[0]
-                .pos
-                .y
-                .max(tri.vert[1].pos.y.max(tri.vert[2].pos.y)),
-            tri.vert[0]
-                .pos
-                .z
-                .max(tri.vert[1].pos.z.max(tri.vert[2].pos.z)),
-        ) + Vector3::repeat(0.00000001),
-        min: Vector3::new(
-            tri.vert[0]
-                .pos
-                .x
-                .min(tri.vert[1].pos.x.min(tri.vert[2].pos.x)),
-            tri.vert[0]
-                .pos
-                .y
-                .min(tri.vert[1].pos.y.min(tri.vert[2].pos.y)),
-            tri.vert[0]
-                .pos
-                .z
-                .min(tri.vert[1].pos.z.min(tri.vert[2].pos.z)),
-        ) - Vector3::repeat(0.00000001),
-    }
-}
-
-fn refs_bounds(refs: &[TriangleRef]) -> Bounds {
+fn refs_bounds(refs: &[BuildRef]) -> Bounds {
     let mut a = refs[0].bounds.clone();
 
     for tri_ref in refs[1..refs.len()].iter() {
@@ -222,34 +1008,256 @@ fn refs_bounds(refs: &[TriangleRef]) -> Bounds {
     a
 }
 
-fn build_refs(mesh: AggregatePrimitive<Triangle>) -> Vec<TriangleRef> {
-    let mut refs: Vec<TriangleRef> = vec![];
+fn centroid_order(a: &BuildRef, b: &BuildRef, axis: SortAxis) -> std::cmp::Ordering {
+    a.centroid[axis as usize]
+        .partial_cmp(&b.centroid[axis as usize])
+        .unwrap()
+}
+
+// Moves the references matching `pred` to the front, returns their count.
+fn partition(refs: &mut [BuildRef], pred: impl Fn(&BuildRef) -> bool) -> usize {
+    let mut middle = 0;
 
-    for prim in mesh.primitives {
-        refs.push(TriangleRef {
-            bounds: triangle_bounds(&prim),
-            tri_ref: prim,
-        })
+    for i in 0..refs.len() {
+        if pred(&refs[i]) {
+            refs.swap(i, middle);
+            middle += 1;
+        }
+    }
+
+    middle
+}
+
+// Builds both children, in parallel for large nodes.
+fn build_children<L, R>(n: usize, left: L, right: R) -> (Node, Node)
+where
+    L: FnOnce() -> Node + Send,
+    R: FnOnce() -> Node + Send,
+{
+    if n >= PARALLEL_THRESHOLD {
+        rayon::join(left, right)
+    } else {
+        (left(), right())
     }
+}
 
-    refs
+#[derive(Clone, Copy, PartialEq)]
+pub enum Builder {
+    Median,
+    Sah,
 }
 
-fn sort_refs(refs: &mut [TriangleRef], axis: SortAxis) {
-    refs.sort_unstable_by(|a, b| {
-        let c0 = (a.bounds.max + a.bounds.min) / 2.0;
-        let c1 = (b.bounds.max + b.bounds.min) / 2.0;
+// `traversal_cost` and `intersection_cost` are the relative costs of
+// visiting a node and intersecting a triangle. `compare` reports the SAH
+// cost and the nodes visited per ray of both builders for every tree,
+// `stats` the shape and build time of every tree. With `cache` the trees of
+// a model are stored next to it and reused while the model and the settings
+// building them stay the same. `refit_threshold` is the factor the SAH cost
+// of a refitted tree may grow by before it is rebuilt.
+#[derive(Clone)]
+pub struct BuildSettings {
+    pub builder: Builder,
+    pub bins: usize,
+    pub traversal_cost: f64,
+    pub intersection_cost: f64,
+    pub max_leaf_size: usize,
+    pub compare: bool,
+    pub stats: bool,
+    pub cache: bool,
+    pub refit_threshold: Option<f64>,
+    pub width: usize,
+    pub benchmark: bool,
+}
 
-        c0[axis as usize].partial_cmp(&c1[axis as usize]).unwrap()
-    });
+impl Default for BuildSettings {
+    fn default() -> BuildSettings {
+        BuildSettings {
+            builder: Builder::Sah,
+            bins: 16,
+            traversal_cost: 1.0,
+            intersection_cost: 1.0,
+            max_leaf_size: 4,
+            compare: false,
+            stats: false,
+            cache: false,
+            refit_threshold: None,
+            width: 2,
+            benchmark: false,
+        }
+    }
 }
 
-fn build_node<'a>(mut refs: Vec<TriangleRef>) -> Node {
-    if refs.len() <= 3 {
-        return Node::Leaf(LeafNode { refs });
+// Prints how the median and SAH builders do on `mesh`.
+pub fn compare_builders<T: Primitive + Bounded + Clone>(
+    name: &str,
+    mesh: &AggregatePrimitive<T>,
+    settings: &BuildSettings,
+) {
+    const RAYS: u32 = 4096;
+
+    for builder in &[Builder::Median, Builder::Sah] {
+        let settings = BuildSettings {
+            builder: *builder,
+            ..settings.clone()
+        };
+
+        let tree = Tree::with_settings(mesh.clone(), &settings);
+
+        println!(
+            "{} {}: {} primitives, SAH cost {:.2}, {:.2} nodes visited per ray",
+            name,
+            if *builder == Builder::Median {
+                "median"
+            } else {
+                "sah"
+            },
+            mesh.primitives.len(),
+            tree.sah_cost(&settings),
+            tree.average_visits(RAYS)
+        );
     }
+}
 
-    let bounds = refs_bounds(&refs);
+// Binned SAH split (Wald, "On fast Construction of SAH-based Bounding
+// Volume Hierarchies"). Centroids are binned along each axis and the
+// cheapest plane between bins is taken, unless a leaf is cheaper and small
+// enough.
+fn build_node_sah(refs: &mut [BuildRef], settings: &BuildSettings) -> Node {
+    let n = refs.len();
+
+    if n <= 1 {
+        return Node::Leaf(LeafNode { count: n });
+    }
+
+    let bounds = refs_bounds(refs);
+
+    let mut centroid_bounds = Bounds {
+        min: refs[0].centroid,
+        max: refs[0].centroid,
+    };
+
+    for r in refs[1..].iter() {
+        centroid_bounds = centroid_bounds.union(&Bounds {
+            min: r.centroid,
+            max: r.centroid,
+        });
+    }
+
+    let extent = centroid_bounds.max - centroid_bounds.min;
+    let bins = settings.bins.max(2);
+
+    let bin_index = |r: &BuildRef, axis: usize| {
+        let offset = (r.centroid[axis] - centroid_bounds.min[axis]) / extent[axis];
+        ((offset * bins as f64) as usize).min(bins - 1)
+    };
+
+    let mut best: Option<(f64, usize, usize)> = None;
+
+    for axis in 0..3 {
+        if extent[axis] <= 0.0 {
+            continue;
+        }
+
+        let mut counts = vec![0; bins];
+        let mut bin_bounds: Vec<Option<Bounds>> = vec![None; bins];
+
+        for r in refs.iter() {
+            let b = bin_index(r, axis);
+
+            counts[b] += 1;
+            bin_bounds[b] = Some(match &bin_bounds[b] {
+                Some(bb) => bb.union(&r.bounds),
+                None => r.bounds.clone(),
+            });
+        }
+
+        // Area times count of everything right of each plane, swept from
+        // the right.
+        let mut right_cost = vec![0.0; bins];
+        let mut acc: Option<Bounds> = None;
+        let mut count = 0;
+
+        for b in (1..bins).rev() {
+            acc = union_option(acc, &bin_bounds[b]);
+            count += counts[b];
+            right_cost[b] = acc.as_ref().map_or(0.0, |a| a.surface_area()) * count as f64;
+        }
+
+        let mut acc: Option<Bounds> = None;
+        let mut count = 0;
+
+        for b in 0..bins - 1 {
+            acc = union_option(acc, &bin_bounds[b]);
+            count += counts[b];
+
+            if count == 0 || count == n {
+                continue;
+            }
+
+            let left_cost = acc.as_ref().map_or(0.0, |a| a.surface_area()) * count as f64;
+            let cost = left_cost + right_cost[b + 1];
+
+            if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, axis, b));
+            }
+        }
+    }
+
+    let leaf_cost = settings.intersection_cost * n as f64;
+
+    let (axis, split) = match best {
+        Some((cost, axis, split)) => {
+            let split_cost =
+                settings.traversal_cost + settings.intersection_cost * cost / bounds.surface_area();
+
+            if split_cost >= leaf_cost && n <= settings.max_leaf_size {
+                return Node::Leaf(LeafNode { count: n });
+            }
+
+            (axis, split)
+        }
+
+        // All centroids coincide, only a median split can cut these down.
+        None if n > settings.max_leaf_size => return build_node(refs),
+        None => return Node::Leaf(LeafNode { count: n }),
+    };
+
+    let middle = partition(refs, |r| bin_index(r, axis) <= split);
+    let (left_refs, right_refs) = refs.split_at_mut(middle);
+
+    let left_bounds = refs_bounds(left_refs);
+    let right_bounds = refs_bounds(right_refs);
+
+    let (left, right) = build_children(
+        n,
+        || build_node_sah(left_refs, settings),
+        || build_node_sah(right_refs, settings),
+    );
+
+    Node::Internal(InternalNode {
+        left_bounds,
+        right_bounds,
+        left: Box::new(left),
+        right: Box::new(right),
+    })
+}
+
+fn union_option(a: Option<Bounds>, b: &Option<Bounds>) -> Option<Bounds> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.union(b)),
+        (Some(a), None) => Some(a),
+        (None, b) => b.clone(),
+    }
+}
+
+fn build_node(refs: &mut [BuildRef]) -> Node {
+    let n = refs.len();
+
+    if n <= 3 {
+        return Node::Leaf(LeafNode { count: n });
+    }
+
+    let bounds = refs_bounds(refs);
     let v = bounds.max - bounds.min;
 
     let sort_axis = if v.x > v.y && v.x > v.z {
@@ -260,17 +1268,159 @@ fn build_node<'a>(mut refs: Vec<TriangleRef>) -> Node {
         SortAxis::Z
     };
 
-    sort_refs(&mut refs[..],  sort_axis);
+    // Only the median has to be in place, not the whole order.
+    let middle = n / 2;
+    refs.select_nth_unstable_by(middle, |a, b| centroid_order(a, b, sort_axis));
+
+    let (left_refs, right_refs) = refs.split_at_mut(middle);
+
+    let left_bounds = refs_bounds(left_refs);
+    let right_bounds = refs_bounds(right_refs);
+
+    let (left, right) = build_children(n, || build_node(left_refs), || build_node(right_refs));
+
+    Node::Internal(InternalNode {
+        left_bounds,
+        right_bounds,
+        left: Box::new(left),
+        right: Box::new(right),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Grid of `n` by `n` quads in the xz plane, lifted by `height`.
+    pub(crate) fn grid(n: u32, height: impl Fn(f64, f64) -> f64) -> IndexedMesh {
+        let mut positions = vec![];
+
+        for j in 0..=n {
+            for i in 0..=n {
+                let (x, z) = (i as f64, j as f64);
+                positions.push([x as f32, height(x, z) as f32, z as f32]);
+            }
+        }
+
+        let mut indices = vec![];
+
+        for j in 0..n {
+            for i in 0..n {
+                let k = j * (n + 1) + i;
+                indices.push([k, k + 1, k + n + 1]);
+                indices.push([k + 1, k + n + 2, k + n + 1]);
+            }
+        }
+
+        IndexedMesh {
+            normals: vec![[0.0, 1.0, 0.0]; positions.len()],
+            uvs: vec![],
+            positions,
+            indices,
+        }
+    }
+
+    pub(crate) fn triangles(mesh: IndexedMesh) -> AggregatePrimitive<MeshTriangle> {
+        let mesh = Arc::new(mesh);
+
+        AggregatePrimitive {
+            primitives: (0..mesh.indices.len() as u32)
+                .map(|index| MeshTriangle {
+                    mesh: mesh.clone(),
+                    index,
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn wave(x: f64, z: f64) -> f64 {
+        3.0 * (0.7 * x).sin() * (0.4 * z).cos()
+    }
+
+    fn assert_same_bounds(a: &Bounds, b: &Bounds) {
+        assert_eq!(a.min, b.min);
+        assert_eq!(a.max, b.max);
+    }
+
+    // Asserts that every node bounds exactly the primitives below it and
+    // returns those bounds.
+    fn check_node(tree: &Tree<MeshTriangle>, index: usize) -> Bounds {
+        let node = &tree.nodes[index];
+
+        let bounds = if node.count > 0 {
+            let start = node.offset as usize;
+
+            tree.primitives[start..start + node.count as usize]
+                .iter()
+                .map(|p| p.aabb())
+                .reduce(|a, b| a.union(&b))
+                .unwrap()
+        } else {
+            check_node(tree, index + 1).union(&check_node(tree, node.offset as usize))
+        };
+
+        assert_same_bounds(&node.bounds, &bounds);
+        bounds
+    }
+
+    pub(crate) fn hits(tree: &Tree<MeshTriangle>) -> Vec<Option<f64>> {
+        let mut hits = vec![];
+
+        for j in 0..40 {
+            for i in 0..40 {
+                let origin = Point3::new(i as f64 * 0.41 - 0.5, 10.0, j as f64 * 0.39 - 0.5);
+                let ray = Ray::new(origin, Vector3::new(0.1, -1.0, 0.05).normalize(), 0.0);
+
+                hits.push(Primitive::intersect(tree, &ray).map(|r| r.t));
+            }
+        }
+
+        hits
+    }
+
+    #[test]
+    fn refit_matches_fresh_build() {
+        let settings = BuildSettings::default();
+
+        let mut tree = Tree::with_settings(triangles(grid(12, |_, _| 0.0)), &settings);
+        let rebuilt = tree.update_mesh(grid(12, wave), &settings).unwrap();
+        let fresh = Tree::with_settings(triangles(grid(12, wave)), &settings);
+
+        assert!(!rebuilt);
+        assert_same_bounds(&check_node(&tree, 0), &fresh.nodes[0].bounds);
+        assert_eq!(hits(&tree), hits(&fresh));
+    }
+
+    #[test]
+    fn update_rebuilds_past_threshold() {
+        let settings = BuildSettings {
+            refit_threshold: Some(1.5),
+            ..BuildSettings::default()
+        };
+
+        // Mirroring the grid sends every triangle across the tree.
+        let mut tree = Tree::with_settings(triangles(grid(12, |_, _| 0.0)), &settings);
+        let mirrored = |n| {
+            let mut mesh = grid(n, wave);
+            mesh.positions.reverse();
+            mesh
+        };
+
+        let rebuilt = tree.update_mesh(mirrored(12), &settings).unwrap();
+        let fresh = Tree::with_settings(triangles(mirrored(12)), &settings);
 
-    let middle = (refs.len() as f64 / 2.0).floor() as usize;
+        assert!(rebuilt);
+        check_node(&tree, 0);
+        assert!(tree.sah_cost(&settings) <= tree.build_cost * 1.5);
+        assert_eq!(hits(&tree), hits(&fresh));
+    }
+
+    #[test]
+    fn update_rejects_other_triangles() {
+        let settings = BuildSettings::default();
 
-    let left_refs = refs[0..middle].to_vec();
-    let right_refs = refs[middle..refs.len()].to_vec();
+        let mut tree = Tree::with_settings(triangles(grid(4, |_, _| 0.0)), &settings);
 
-    return Node::Internal(InternalNode {
-        left_bounds: refs_bounds(&left_refs),
-        right_bounds: refs_bounds(&right_refs),
-        left: Box::new(build_node(left_refs)),
-        right: Box::new(build_node(right_refs)),
-    });
+        assert!(tree.update_mesh(grid(5, wave), &settings).is_err());
+    }
 }
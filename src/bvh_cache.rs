@@ -0,0 +1,173 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use crate::binary::{read_u32, read_u64};
+use crate::bvh::{BuildSettings, Builder, Tree};
+use crate::primitive::MeshTriangle;
+use crate::scene::{fnv1a, FNV_OFFSET};
+
+// Little endian binary layout:
+//
+//   magic "RTBV", version u32, key u64, group count u32
+//   per group: name length u32, name bytes, tree (see `Tree::write`)
+//
+// The key hashes the OBJ file and the settings the trees were built with.
+// Materials aren't cached, they are read from the metadata of the model.
+const MAGIC: &[u8; 4] = b"RTBV";
+const VERSION: u32 = 4;
+
+// Trees of the groups of a model by group name, in group order.
+pub type Groups = Vec<(String, Tree<MeshTriangle>)>;
+
+pub fn key(obj: &[u8], settings: &BuildSettings) -> u64 {
+    let mut hash = fnv1a(FNV_OFFSET, obj);
+
+    hash = fnv1a(
+        hash,
+        &[match settings.builder {
+            Builder::Median => 0,
+            Builder::Sah => 1,
+        }],
+    );
+
+    for v in &[settings.bins as u64, settings.max_leaf_size as u64] {
+        hash = fnv1a(hash, &v.to_le_bytes());
+    }
+
+    for v in &[settings.traversal_cost, settings.intersection_cost] {
+        hash = fnv1a(hash, &v.to_le_bytes());
+    }
+
+    hash
+}
+
+// Written to a temporary file first so an interrupted write never leaves a
+// broken cache behind.
+pub fn save(path: &str, key: u64, groups: &Groups) -> Result<(), Box<dyn Error>> {
+    let tmp_path = path.to_owned() + ".tmp";
+
+    {
+        let mut w = BufWriter::new(File::create(&tmp_path)?);
+
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&key.to_le_bytes())?;
+        w.write_all(&(groups.len() as u32).to_le_bytes())?;
+
+        for (name, tree) in groups {
+            w.write_all(&(name.len() as u32).to_le_bytes())?;
+            w.write_all(name.as_bytes())?;
+            tree.write(&mut w)?;
+        }
+
+        w.flush()?;
+    }
+
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+// Fails on missing and stale caches alike, callers rebuild either way.
+pub fn load(path: &str, key: u64) -> Result<Groups, Box<dyn Error>> {
+    let mut r = BufReader::new(File::open(path)?);
+
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+
+    if &magic != MAGIC {
+        return Err(format!("{} is not a BVH cache", path).into());
+    }
+
+    if read_u32(&mut r)? != VERSION {
+        return Err("Unsupported BVH cache version".into());
+    }
+
+    if read_u64(&mut r)? != key {
+        return Err("BVH cache is stale".into());
+    }
+
+    let mut groups = vec![];
+
+    for _ in 0..read_u32(&mut r)? {
+        let mut name = vec![0; read_u32(&mut r)? as usize];
+        r.read_exact(&mut name)?;
+
+        groups.push((String::from_utf8(name)?, Tree::read(&mut r)?));
+    }
+
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bvh::tests::{grid, hits, triangles, wave};
+    use crate::primitive::IndexedMesh;
+
+    #[test]
+    fn caches_round_trip() {
+        let settings = BuildSettings::default();
+
+        let mut textured = grid(6, wave);
+        textured.uvs = textured.positions.iter().map(|p| [p[0], p[2]]).collect();
+
+        let mut groups: Groups = vec![
+            (
+                "flat".to_owned(),
+                Tree::with_settings(triangles(grid(8, |_, _| 0.0)), &settings),
+            ),
+            (
+                "textured".to_owned(),
+                Tree::with_settings(triangles(textured), &settings),
+            ),
+        ];
+
+        let path =
+            std::env::temp_dir().join(format!("rusttracer-bvh-{}.cache", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        save(path, 7, &groups).unwrap();
+        let mut loaded = load(path, 7).unwrap();
+
+        assert!(load(path, 8).is_err());
+        std::fs::remove_file(path).unwrap();
+
+        for ((name, tree), (loaded_name, loaded_tree)) in groups.iter_mut().zip(loaded.iter_mut()) {
+            assert_eq!(name, loaded_name);
+            assert_eq!(tree.nodes.len(), loaded_tree.nodes.len());
+
+            for (a, b) in tree.nodes.iter().zip(loaded_tree.nodes.iter()) {
+                assert_eq!((a.offset, a.count), (b.offset, b.count));
+            }
+
+            let (a, b) = (&tree.primitives[0].mesh, &loaded_tree.primitives[0].mesh);
+            assert_eq!(a.positions, b.positions);
+            assert_eq!(a.normals, b.normals);
+            assert_eq!(a.uvs, b.uvs);
+            assert_eq!(a.indices, b.indices);
+
+            assert_eq!(hits(tree), hits(loaded_tree));
+
+            // The primitive order survives, so loaded trees refit alike.
+            let moved = || IndexedMesh {
+                positions: a
+                    .positions
+                    .iter()
+                    .map(|p| [p[0], p[1] + p[0] * p[2], p[2]])
+                    .collect(),
+                normals: a.normals.clone(),
+                uvs: a.uvs.clone(),
+                indices: a.indices.clone(),
+            };
+
+            let (mesh, loaded_mesh) = (moved(), moved());
+            tree.update_mesh(mesh, &settings).unwrap();
+            loaded_tree.update_mesh(loaded_mesh, &settings).unwrap();
+
+            assert_eq!(hits(tree), hits(loaded_tree));
+        }
+    }
+}
@@ -1,63 +1,307 @@
 extern crate nalgebra as na;
 use na::{Isometry3, Point3};
 use na::{Vector2, Vector3};
-use rand::random;
 
+use std::f64::consts::PI;
+
+use crate::motion::AnimatedIsometry;
 use crate::ray::Ray;
 
-pub struct Camera {
-    pub isometry: Isometry3<f64>,
+pub trait Camera: Send + Sync {
+    fn img_dimensions(&self) -> Vector2<u32>;
+
+    // `p` is in continuous film coordinates, pixel (i, j) covers
+    // [i, i + 1) x [j, j + 1).
+    fn to_screen_space(&self, p: &Vector2<f64>) -> Vector2<f64>;
+
+    fn generate_ray(&self, p: &Vector2<f64>, time: f64) -> Option<Ray>;
+
+    fn get_ray(&self, p: &Vector2<f64>, time: f64) -> Option<Ray> {
+        self.generate_ray(&self.to_screen_space(p), time)
+    }
+
+    fn img_ratio(&self) -> f64 {
+        let dimensions = self.img_dimensions();
+        dimensions.x as f64 / dimensions.y as f64
+    }
+
+    // Connection from the world point `p` to the camera, for light paths
+    // reaching the film. None if `p` is outside of the view or the camera
+    // can't be connected to.
+    fn sample_importance(&self, _p: &Point3<f64>, _time: f64) -> Option<ImportanceSample> {
+        None
+    }
+
+    // Solid angle pdf of `generate_ray` producing the world space
+    // `direction`, None if the camera can't be connected to.
+    fn pdf(&self, _direction: &Vector3<f64>, _time: f64) -> Option<f64> {
+        None
+    }
+}
+
+// `raster` is in continuous film coordinates, `importance` is emitted from
+// `origin` towards the point and `pdf` is per unit solid angle at the point.
+pub struct ImportanceSample {
+    pub raster: Vector2<f64>,
+    pub origin: Point3<f64>,
+    pub importance: f64,
+    pub pdf: f64,
+}
+
+// Camera to world transform.
+pub fn look_at(
+    origin: &Point3<f64>,
+    direction: &Vector3<f64>,
+    up: &Vector3<f64>,
+) -> Isometry3<f64> {
+    Isometry3::look_at_rh(origin, &(origin + direction), up).inverse()
+}
+
+fn centered_screen_space(
+    img_dimensions: &Vector2<u32>,
+    ratio: f64,
+    p: &Vector2<f64>,
+) -> Vector2<f64> {
+    Vector2::<f64>::new(
+        ((p.x / img_dimensions.x as f64) - 0.5) * 2.0 * ratio,
+        -((p.y / img_dimensions.y as f64) - 0.5) * 2.0,
+    )
+}
+
+pub struct PerspectiveCamera {
+    pub motion: AnimatedIsometry,
     pub img_dimensions: Vector2<u32>,
     pub fov: f64,
 }
 
-impl Camera {
+impl PerspectiveCamera {
     pub fn new(
-        origin: &Point3<f64>,
-        direction: &Vector3<f64>,
+        motion: AnimatedIsometry,
         img_dimensions: Vector2<u32>,
         fov: f64,
-    ) -> Camera {
-        Camera {
-            isometry: Isometry3::look_at_rh(origin, &(origin + direction), &Vector3::y_axis()),
-            img_dimensions: img_dimensions,
+    ) -> PerspectiveCamera {
+        PerspectiveCamera {
+            motion,
+            img_dimensions,
             fov,
         }
     }
 
-    pub fn get_ray(&self, i: u32, j: u32) -> Ray {
-        let p = self.to_screen_space(i, j);
+    // Half the height of the image plane at distance 1.
+    fn tan(&self) -> f64 {
+        (self.fov / 2.0).to_radians().tan()
+    }
 
-        let v = Vector3::<f64>::new(
-            p.x + random::<f64>() / (self.img_dimensions.x as f64),
-            p.y + random::<f64>() / (self.img_dimensions.y as f64),
-            -1.0 / (self.fov / 2.0).to_radians().tan(),
-        )
-        .normalize();
+    // Image plane area at distance 1, the importance is normalized over it.
+    fn plane_area(&self) -> f64 {
+        4.0 * self.img_ratio() * self.tan() * self.tan()
+    }
+
+    // Screen space point of the camera space direction `v`, None if it
+    // points out of the view.
+    fn screen_point(&self, v: &Vector3<f64>) -> Option<Vector2<f64>> {
+        if v.z >= 0.0 {
+            return None;
+        }
+
+        let p = Vector2::new(v.x, v.y) / (-v.z * self.tan());
+
+        if p.x.abs() > self.img_ratio() || p.y.abs() > 1.0 {
+            None
+        } else {
+            Some(p)
+        }
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn img_dimensions(&self) -> Vector2<u32> {
+        self.img_dimensions
+    }
+
+    fn to_screen_space(&self, p: &Vector2<f64>) -> Vector2<f64> {
+        centered_screen_space(&self.img_dimensions, self.img_ratio(), p)
+    }
+
+    fn generate_ray(&self, p: &Vector2<f64>, time: f64) -> Option<Ray> {
+        let m = self.motion.at(time);
+
+        let v = Vector3::<f64>::new(p.x, p.y, -1.0 / self.tan()).normalize();
+
+        Some(Ray::new(m * Point3::origin(), m * v, time))
+    }
+
+    fn sample_importance(&self, p: &Point3<f64>, time: f64) -> Option<ImportanceSample> {
+        let m = self.motion.at(time);
+        let origin = m * Point3::origin();
+
+        let d = p - origin;
+        let v = m.inverse_transform_vector(&d).normalize();
+        let s = self.screen_point(&v)?;
+
+        let dimensions = self.img_dimensions.map(|x| x as f64);
+        let cos = -v.z;
+
+        Some(ImportanceSample {
+            raster: Vector2::new(
+                (s.x / (2.0 * self.img_ratio()) + 0.5) * dimensions.x,
+                (0.5 - s.y / 2.0) * dimensions.y,
+            ),
+            origin,
+            importance: 1.0 / (self.plane_area() * cos.powi(4)),
+            pdf: d.norm_squared() / cos,
+        })
+    }
+
+    fn pdf(&self, direction: &Vector3<f64>, time: f64) -> Option<f64> {
+        let v = self
+            .motion
+            .at(time)
+            .inverse_transform_vector(direction)
+            .normalize();
+
+        Some(match self.screen_point(&v) {
+            Some(_) => 1.0 / (self.plane_area() * (-v.z).powi(3)),
+            None => 0.0,
+        })
+    }
+}
+
+// `scale` is half the height of the view volume in world units.
+pub struct OrthographicCamera {
+    pub motion: AnimatedIsometry,
+    pub img_dimensions: Vector2<u32>,
+    pub scale: f64,
+}
 
-        // let v = Vector3::<f64>::new(
-        //     p.x,
-        //     p.y,
-        //     -1.0 / (self.fov / 2.0).to_radians().tan(),
-        // )
-        // .normalize();
+impl OrthographicCamera {
+    pub fn new(
+        motion: AnimatedIsometry,
+        img_dimensions: Vector2<u32>,
+        scale: f64,
+    ) -> OrthographicCamera {
+        OrthographicCamera {
+            motion,
+            img_dimensions,
+            scale,
+        }
+    }
+}
 
-        Ray {
-            origin: self.isometry.inverse_transform_point(&Point3::origin()),
-            direction: self.isometry.inverse_transform_vector(&v),
+impl Camera for OrthographicCamera {
+    fn img_dimensions(&self) -> Vector2<u32> {
+        self.img_dimensions
+    }
+
+    fn to_screen_space(&self, p: &Vector2<f64>) -> Vector2<f64> {
+        centered_screen_space(&self.img_dimensions, self.img_ratio(), p)
+    }
+
+    fn generate_ray(&self, p: &Vector2<f64>, time: f64) -> Option<Ray> {
+        let m = self.motion.at(time);
+
+        let o = Point3::<f64>::new(p.x * self.scale, p.y * self.scale, 0.0);
+
+        Some(Ray::new(m * o, m * -Vector3::z(), time))
+    }
+}
+
+// Equidistant fisheye, the image circle is inscribed in the smaller image
+// dimension and pixels outside of it produce no rays.
+pub struct FisheyeCamera {
+    pub motion: AnimatedIsometry,
+    pub img_dimensions: Vector2<u32>,
+    pub fov: f64,
+}
+
+impl FisheyeCamera {
+    pub fn new(motion: AnimatedIsometry, img_dimensions: Vector2<u32>, fov: f64) -> FisheyeCamera {
+        FisheyeCamera {
+            motion,
+            img_dimensions,
+            fov,
         }
     }
+}
 
-    pub fn img_ratio(&self) -> f64 {
-        self.img_dimensions.x as f64 / self.img_dimensions.y as f64
+impl Camera for FisheyeCamera {
+    fn img_dimensions(&self) -> Vector2<u32> {
+        self.img_dimensions
     }
 
-    pub fn to_screen_space(&self, i: u32, j: u32) -> Vector2<f64> {
+    fn to_screen_space(&self, p: &Vector2<f64>) -> Vector2<f64> {
         let ratio = self.img_ratio();
 
+        if ratio > 1.0 {
+            centered_screen_space(&self.img_dimensions, ratio, p)
+        } else {
+            centered_screen_space(&self.img_dimensions, 1.0, p)
+                .component_div(&Vector2::new(1.0, ratio))
+        }
+    }
+
+    fn generate_ray(&self, p: &Vector2<f64>, time: f64) -> Option<Ray> {
+        let m = self.motion.at(time);
+
+        let r = p.norm();
+
+        if r > 1.0 {
+            return None;
+        }
+
+        let theta = r * (self.fov / 2.0).to_radians();
+        let phi = p.y.atan2(p.x);
+
+        let v = Vector3::<f64>::new(
+            theta.sin() * phi.cos(),
+            theta.sin() * phi.sin(),
+            -theta.cos(),
+        );
+
+        Some(Ray::new(m * Point3::origin(), m * v, time))
+    }
+}
+
+// Full 360x180 latitude-longitude panorama, the image center looks along
+// the camera direction.
+pub struct EquirectangularCamera {
+    pub motion: AnimatedIsometry,
+    pub img_dimensions: Vector2<u32>,
+}
+
+impl EquirectangularCamera {
+    pub fn new(motion: AnimatedIsometry, img_dimensions: Vector2<u32>) -> EquirectangularCamera {
+        EquirectangularCamera {
+            motion,
+            img_dimensions,
+        }
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn img_dimensions(&self) -> Vector2<u32> {
+        self.img_dimensions
+    }
+
+    fn to_screen_space(&self, p: &Vector2<f64>) -> Vector2<f64> {
         Vector2::<f64>::new(
-            ((i as f64 / self.img_dimensions.x as f64) - 0.5) * 2.0 * ratio,
-            -((j as f64 / self.img_dimensions.y as f64) - 0.5) * 2.0,
+            p.x / self.img_dimensions.x as f64,
+            p.y / self.img_dimensions.y as f64,
         )
     }
+
+    fn generate_ray(&self, p: &Vector2<f64>, time: f64) -> Option<Ray> {
+        let m = self.motion.at(time);
+
+        let phi = (p.x - 0.5) * 2.0 * PI;
+        let theta = p.y * PI;
+
+        let v = Vector3::<f64>::new(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        );
+
+        Some(Ray::new(m * Point3::origin(), m * v, time))
+    }
 }
@@ -0,0 +1,263 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use crate::binary::{read_f64, read_u32, read_u64, read_vector, write_vector};
+use crate::film::{Aovs, Film, Pixel};
+use crate::scene::Scene;
+
+// Little endian binary layout:
+//
+//   magic "RTCK", version u32, scene hash u64
+//   width u32, height u32, spp u32
+//   sampler name length u32, sampler name bytes, sampler seed u32
+//   per pixel: sum 3 x f64, weight f64, samples u32, luminance sum f64,
+//   luminance square sum f64, splat sum 3 x f64, AOVs
+//
+// AOVs are the sums of albedo, normal, depth, position, direct, indirect,
+// diffuse and specular as f64, then material and group ID as i64 with -1
+// for none.
+//
+// Samplers are stateless functions of seed, pixel and sample index, so the
+// per pixel sample counts are all that is needed to continue the sequences.
+// Everything else that changes the image, like the path depth, is covered
+// by the scene hash.
+const MAGIC: &[u8; 4] = b"RTCK";
+const VERSION: u32 = 6;
+
+// Written to a temporary file first so an interrupted write never replaces
+// a good checkpoint.
+pub fn save(path: &str, scene: &Scene, film: &Film) -> Result<(), Box<dyn Error>> {
+    let tmp_path = path.to_owned() + ".tmp";
+    let hash = scene.hash()?;
+
+    {
+        let mut w = BufWriter::new(File::create(&tmp_path)?);
+        let settings = &scene.settings;
+        let name = scene.sampler.name().as_bytes();
+
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&hash.to_le_bytes())?;
+        w.write_all(&film.width.to_le_bytes())?;
+        w.write_all(&film.height.to_le_bytes())?;
+        w.write_all(&settings.spp.to_le_bytes())?;
+        w.write_all(&(name.len() as u32).to_le_bytes())?;
+        w.write_all(name)?;
+        w.write_all(&scene.sampler.seed().to_le_bytes())?;
+
+        for pixel in &film.pixels {
+            write_vector(&mut w, &pixel.sum)?;
+            w.write_all(&pixel.weight.to_le_bytes())?;
+            w.write_all(&pixel.samples.to_le_bytes())?;
+            w.write_all(&pixel.lum_sum.to_le_bytes())?;
+            w.write_all(&pixel.lum_sq_sum.to_le_bytes())?;
+            write_vector(&mut w, &pixel.splat)?;
+            write_aovs(&mut w, &pixel.aovs)?;
+        }
+
+        w.flush()?;
+    }
+
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+fn write_aovs(w: &mut impl Write, aovs: &Aovs) -> Result<(), Box<dyn Error>> {
+    write_vector(w, &aovs.albedo)?;
+    write_vector(w, &aovs.normal)?;
+    w.write_all(&aovs.depth.to_le_bytes())?;
+    write_vector(w, &aovs.position)?;
+    write_vector(w, &aovs.direct)?;
+    write_vector(w, &aovs.indirect)?;
+    write_vector(w, &aovs.diffuse)?;
+    write_vector(w, &aovs.specular)?;
+
+    for id in &[aovs.material_id, aovs.group_id] {
+        w.write_all(&id.map_or(-1, |id| id as i64).to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn read_id(r: &mut impl Read) -> Result<Option<u32>, Box<dyn Error>> {
+    let id = read_u64(r)? as i64;
+    Ok(if id < 0 { None } else { Some(id as u32) })
+}
+
+fn read_aovs(r: &mut impl Read) -> Result<Aovs, Box<dyn Error>> {
+    // Fields are read in the order they are written in.
+    Ok(Aovs {
+        albedo: read_vector(r)?,
+        normal: read_vector(r)?,
+        depth: read_f64(r)?,
+        position: read_vector(r)?,
+        direct: read_vector(r)?,
+        indirect: read_vector(r)?,
+        diffuse: read_vector(r)?,
+        specular: read_vector(r)?,
+        material_id: read_id(r)?,
+        group_id: read_id(r)?,
+    })
+}
+
+// Loads a film to continue rendering `scene` from. Refuses checkpoints of a
+// different scene, resolution or sample sequence.
+pub fn load(path: &str, scene: &Scene) -> Result<Film, Box<dyn Error>> {
+    let mut r = BufReader::new(File::open(path)?);
+    let settings = &scene.settings;
+
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+
+    if &magic != MAGIC {
+        return Err(format!("{} is not a checkpoint", path).into());
+    }
+
+    let version = read_u32(&mut r)?;
+    if version != VERSION {
+        return Err(format!("Unsupported checkpoint version {}", version).into());
+    }
+
+    if read_u64(&mut r)? != scene.hash()? {
+        return Err("Checkpoint was rendered from a different scene".into());
+    }
+
+    let width = read_u32(&mut r)?;
+    let height = read_u32(&mut r)?;
+    if width != settings.width || height != settings.height {
+        return Err(format!(
+            "Checkpoint resolution {}x{} does not match {}x{}",
+            width, height, settings.width, settings.height
+        )
+        .into());
+    }
+
+    let spp = read_u32(&mut r)?;
+
+    let mut name = vec![0; read_u32(&mut r)? as usize];
+    r.read_exact(&mut name)?;
+    let seed = read_u32(&mut r)?;
+
+    if name != scene.sampler.name().as_bytes() || seed != scene.sampler.seed() {
+        return Err("Checkpoint was rendered with a different sampler".into());
+    }
+
+    // Stratified strata depend on the target sample count.
+    if scene.sampler.name() == "stratified" && spp != settings.spp {
+        return Err("Stratified checkpoints can only resume with the same spp".into());
+    }
+
+    let mut film = Film::new(width, height, scene.filter.clone());
+
+    for pixel in film.pixels.iter_mut() {
+        *pixel = Pixel {
+            sum: read_vector(&mut r)?,
+            weight: read_f64(&mut r)?,
+            samples: read_u32(&mut r)?,
+            lum_sum: read_f64(&mut r)?,
+            lum_sq_sum: read_f64(&mut r)?,
+            splat: read_vector(&mut r)?,
+            aovs: read_aovs(&mut r)?,
+        };
+    }
+
+    Ok(film)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use na::Vector3;
+
+    use crate::scene::load_scene;
+
+    // Scene of one triangle in a directory of its own, rendered `depth`
+    // bounces deep.
+    fn write_scene(dir: &std::path::Path, depth: u32) -> String {
+        let model = dir.join("triangle.obj");
+
+        std::fs::write(
+            &model,
+            "g tri\nv 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("triangle.obj.json"),
+            r#"{"groups": [{"name": "tri", "material": {"name": "diffuse",
+                "color": {"r": 0.5, "g": 0.5, "b": 0.5}}}]}"#,
+        )
+        .unwrap();
+
+        let scene = dir.join(format!("scene{}.json", depth));
+        let model = model.to_str().unwrap();
+
+        std::fs::write(
+            &scene,
+            format!(
+                r#"{{"model": "{}", "width": 4, "height": 3, "depth": {},
+                    "sampler": "halton", "checkpoint": {{}},
+                    "camera": {{"origin": {{"x": 0, "y": 0, "z": 3}},
+                        "target": {{"x": 0, "y": 0, "z": 0}}, "fov": 45}},
+                    "lights": []}}"#,
+                model, depth
+            ),
+        )
+        .unwrap();
+
+        scene.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn checkpoints_round_trip() {
+        let dir =
+            std::env::temp_dir().join(format!("rusttracer-checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let scene = load_scene(&write_scene(&dir, 3)).unwrap();
+        let mut film = Film::new(4, 3, scene.filter.clone());
+
+        for (i, pixel) in film.pixels.iter_mut().enumerate() {
+            let x = i as f64;
+
+            pixel.sum = Vector3::new(x, 0.5 * x, -x);
+            pixel.weight = 1.5 + x;
+            pixel.samples = i as u32 * 7;
+            pixel.lum_sum = 0.25 * x;
+            pixel.lum_sq_sum = 0.125 * x;
+            pixel.splat = Vector3::repeat(x / 3.0);
+            pixel.aovs.depth = x;
+            pixel.aovs.normal = Vector3::z();
+            pixel.aovs.material_id = if i % 2 == 0 { Some(i as u32) } else { None };
+            pixel.aovs.specular = Vector3::new(1.0, x, 2.0);
+        }
+
+        let path = dir.join("film.checkpoint");
+        let path = path.to_str().unwrap();
+
+        save(path, &scene, &film).unwrap();
+        let loaded = load(path, &scene).unwrap();
+
+        for (a, b) in film.pixels.iter().zip(loaded.pixels.iter()) {
+            assert_eq!(a.sum, b.sum);
+            assert_eq!(a.weight, b.weight);
+            assert_eq!(a.samples, b.samples);
+            assert_eq!(a.lum_sum, b.lum_sum);
+            assert_eq!(a.lum_sq_sum, b.lum_sq_sum);
+            assert_eq!(a.splat, b.splat);
+            assert_eq!(a.aovs.depth, b.aovs.depth);
+            assert_eq!(a.aovs.normal, b.aovs.normal);
+            assert_eq!(a.aovs.material_id, b.aovs.material_id);
+            assert_eq!(a.aovs.group_id, b.aovs.group_id);
+            assert_eq!(a.aovs.specular, b.aovs.specular);
+        }
+
+        // A different depth changes the scene hash.
+        let deeper = load_scene(&write_scene(&dir, 4)).unwrap();
+        assert!(load(path, &deeper).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+extern crate nalgebra as na;
+use na::Vector3;
+
+use crate::aov::false_color;
+use crate::film::Aovs;
+use crate::integrator;
+use crate::ray::Ray;
+use crate::sample;
+use crate::sampler::Sampler;
+use crate::scene::{DebugSettings, Scene};
+use crate::stats;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DebugMode {
+    Normal,
+    GeometricNormal,
+    Uv,
+    Depth,
+    Barycentric,
+    MaterialId,
+    GroupId,
+    Cost,
+    AmbientOcclusion,
+    Clay,
+}
+
+pub const ALL: [DebugMode; 10] = [
+    DebugMode::Normal,
+    DebugMode::GeometricNormal,
+    DebugMode::Uv,
+    DebugMode::Depth,
+    DebugMode::Barycentric,
+    DebugMode::MaterialId,
+    DebugMode::GroupId,
+    DebugMode::Cost,
+    DebugMode::AmbientOcclusion,
+    DebugMode::Clay,
+];
+
+impl DebugMode {
+    pub fn from_name(name: &str) -> Option<DebugMode> {
+        ALL.iter().find(|mode| mode.name() == name).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DebugMode::Normal => "normal",
+            DebugMode::GeometricNormal => "geometric_normal",
+            DebugMode::Uv => "uv",
+            DebugMode::Depth => "depth",
+            DebugMode::Barycentric => "barycentric",
+            DebugMode::MaterialId => "material_id",
+            DebugMode::GroupId => "group_id",
+            DebugMode::Cost => "cost",
+            DebugMode::AmbientOcclusion => "ao",
+            DebugMode::Clay => "clay",
+        }
+    }
+}
+
+fn distance(settings: &DebugSettings, scene: &Scene) -> f64 {
+    settings.distance.unwrap_or_else(|| scene.size())
+}
+
+// The film is gamma corrected, so false colors are linearized first to come
+// out as they are in the image.
+fn linear(c: Vector3<f64>) -> Vector3<f64> {
+    c.map(|x| x.max(0.0).powf(2.2))
+}
+
+// Color of the camera `ray` in the debug `settings.mode`. Clay and ambient
+// occlusion are shaded and come out gamma corrected like a normal render,
+// everything else is a false color of the first hit and black on misses.
+pub fn radiance(
+    settings: &DebugSettings,
+    ray: Ray,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+    aovs: &mut Aovs,
+) -> Vector3<f64> {
+    if settings.mode == DebugMode::Clay {
+        return integrator::radiance(scene.settings.depth, ray, scene, sampler, aovs);
+    }
+
+    let cost = stats::local().cost();
+
+    let record = match scene.obj.intersect(&ray) {
+        Some(record) => record,
+        None if settings.mode == DebugMode::Cost => {
+            return linear(stats::ramp(
+                (stats::local().cost() - cost) as f64 / settings.max_cost,
+            ))
+        }
+        None => return Vector3::zeros(),
+    };
+
+    let c = match settings.mode {
+        DebugMode::Normal => (record.normal + Vector3::repeat(1.0)) * 0.5,
+        DebugMode::GeometricNormal => (record.geometric_normal + Vector3::repeat(1.0)) * 0.5,
+        DebugMode::Uv => Vector3::new(
+            record.uv.x - record.uv.x.floor(),
+            record.uv.y - record.uv.y.floor(),
+            0.0,
+        ),
+        DebugMode::Depth => Vector3::repeat(1.0 - record.t / distance(settings, scene)),
+        DebugMode::Barycentric => record.barycentric,
+        DebugMode::MaterialId => false_color(record.material_id),
+        DebugMode::GroupId => false_color(record.group_id),
+        DebugMode::Cost => stats::ramp((stats::local().cost() - cost) as f64 / settings.max_cost),
+        DebugMode::AmbientOcclusion => {
+            let s = sample::SampleRecord::new(&ray, &record);
+            let ao = integrator::unoccluded(&s, scene, sampler, distance(settings, scene), 1);
+
+            return Vector3::repeat(ao);
+        }
+        DebugMode::Clay => unreachable!(),
+    };
+
+    linear(c)
+}
@@ -0,0 +1,144 @@
+extern crate nalgebra as na;
+use na::Vector3;
+
+use rayon::prelude::*;
+
+use crate::film::{luminance, Aovs, Film};
+use crate::scene::DenoiseSettings;
+
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// Edge avoiding À-Trous wavelet filter (Dammertz et al. 2010) with the
+// variance guided color weight of SVGF (Schied et al. 2017). Color is
+// divided by the albedo before filtering so texture detail isn't blurred,
+// and multiplied back afterwards.
+pub fn denoise(film: &Film, settings: &DenoiseSettings) -> Vec<Vector3<f64>> {
+    let (width, height) = (film.width as i64, film.height as i64);
+    let aovs = film.aovs();
+
+    let demodulate = |c: &Vector3<f64>, a: &Vector3<f64>| {
+        Vector3::from_fn(|k, _| if a[k] > 1.0e-3 { c[k] / a[k] } else { c[k] })
+    };
+
+    let mut color: Vec<Vector3<f64>> = film
+        .colors()
+        .iter()
+        .zip(aovs.iter())
+        .map(|(c, f)| demodulate(c, &f.albedo))
+        .collect();
+
+    // Variance of the luminance of the pixel mean.
+    let mut variance: Vec<f64> = film
+        .pixels
+        .iter()
+        .map(|p| p.variance() / p.samples.max(1) as f64)
+        .collect();
+
+    let remodulate = |c: &Vector3<f64>, a: &Vector3<f64>| {
+        Vector3::from_fn(|k, _| if a[k] > 1.0e-3 { c[k] * a[k] } else { c[k] })
+    };
+
+    for iteration in 0..settings.iterations {
+        let step = 1 << iteration;
+
+        let filtered: Vec<(Vector3<f64>, f64)> = (0..width * height)
+            .into_par_iter()
+            .map(|index| {
+                let (x, y) = (index % width, index / width);
+                let p = index as usize;
+
+                let lum_p = luminance(&remodulate(&color[p], &aovs[p].albedo));
+                let sigma_l = settings.sigma_color
+                    * blurred_variance(&variance, x, y, width, height).sqrt()
+                    + 1.0e-6;
+
+                let mut sum = Vector3::zeros();
+                let mut var_sum = 0.0;
+                let mut weight_sum = 0.0;
+
+                for (j, hy) in KERNEL.iter().enumerate() {
+                    for (i, hx) in KERNEL.iter().enumerate() {
+                        let (dx, dy) = (i as i64 - 2, j as i64 - 2);
+                        let (qx, qy) = (x + dx * step, y + dy * step);
+
+                        if qx < 0 || qy < 0 || qx >= width || qy >= height {
+                            continue;
+                        }
+
+                        let q = (qy * width + qx) as usize;
+                        let distance = (((dx * dx + dy * dy) as f64).sqrt() * step as f64).max(1.0);
+
+                        let lum_q = luminance(&remodulate(&color[q], &aovs[q].albedo));
+
+                        let w = hx
+                            * hy
+                            * (-(lum_p - lum_q).abs() / sigma_l).exp()
+                            * feature_weight(&aovs[p], &aovs[q], distance, settings);
+
+                        sum += color[q] * w;
+                        var_sum += variance[q] * w * w;
+                        weight_sum += w;
+                    }
+                }
+
+                if weight_sum > 0.0 {
+                    (sum / weight_sum, var_sum / (weight_sum * weight_sum))
+                } else {
+                    (color[p], variance[p])
+                }
+            })
+            .collect();
+
+        color = filtered.iter().map(|f| f.0).collect();
+        variance = filtered.iter().map(|f| f.1).collect();
+    }
+
+    color
+        .iter()
+        .zip(aovs.iter())
+        .map(|(c, f)| remodulate(c, &f.albedo))
+        .collect()
+}
+
+// The variance of a single pixel is too noisy itself to guide the color
+// weight, it is smoothed with a 3x3 gaussian first.
+fn blurred_variance(variance: &[f64], x: i64, y: i64, width: i64, height: i64) -> f64 {
+    const GAUSSIAN: [f64; 3] = [0.25, 0.5, 0.25];
+
+    let mut sum = 0.0;
+    let mut weight_sum = 0.0;
+
+    for (j, hy) in GAUSSIAN.iter().enumerate() {
+        for (i, hx) in GAUSSIAN.iter().enumerate() {
+            let (qx, qy) = (x + i as i64 - 1, y + j as i64 - 1);
+
+            if qx < 0 || qy < 0 || qx >= width || qy >= height {
+                continue;
+            }
+
+            sum += variance[(qy * width + qx) as usize] * hx * hy;
+            weight_sum += hx * hy;
+        }
+    }
+
+    sum / weight_sum
+}
+
+// Depth differences are relative to the depth of `p` and to the pixel
+// distance, so slanted surfaces far away aren't cut up into strips.
+fn feature_weight(p: &Aovs, q: &Aovs, distance: f64, settings: &DenoiseSettings) -> f64 {
+    let hit_p = p.depth > 0.0;
+    let hit_q = q.depth > 0.0;
+
+    if hit_p != hit_q {
+        return 0.0;
+    } else if !hit_p {
+        return 1.0;
+    }
+
+    let w_normal = p.normal.dot(&q.normal).max(0.0).powf(settings.sigma_normal);
+    let w_depth = (-(p.depth - q.depth).abs() / (settings.sigma_depth * p.depth * distance)).exp();
+    let w_albedo = (-(p.albedo - q.albedo).norm_squared() / settings.sigma_albedo.powi(2)).exp();
+
+    w_normal * w_depth * w_albedo
+}
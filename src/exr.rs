@@ -0,0 +1,92 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// Minimal single part scanline OpenEXR writer, uncompressed 32 bit float
+// channels. Layers are channels named "<layer>.<channel>", the way
+// compositing tools expect multi-layer files.
+pub struct Channel {
+    pub name: String,
+    pub values: Vec<f32>,
+}
+
+fn write_attribute(w: &mut impl Write, name: &str, kind: &str, value: &[u8]) -> io::Result<()> {
+    w.write_all(name.as_bytes())?;
+    w.write_all(&[0])?;
+    w.write_all(kind.as_bytes())?;
+    w.write_all(&[0])?;
+    w.write_all(&(value.len() as i32).to_le_bytes())?;
+    w.write_all(value)
+}
+
+fn box2i(width: u32, height: u32) -> Vec<u8> {
+    [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes().to_vec())
+        .collect()
+}
+
+pub fn write(path: &str, width: u32, height: u32, channels: &mut [Channel]) -> io::Result<()> {
+    // Channels have to be stored in alphabetical order.
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01];
+    header.extend_from_slice(&2u32.to_le_bytes());
+
+    let mut chlist = vec![];
+    for channel in channels.iter() {
+        chlist.extend_from_slice(channel.name.as_bytes());
+        chlist.push(0);
+        // FLOAT pixel type, linear flag, reserved bytes, x and y sampling.
+        chlist.extend_from_slice(&2i32.to_le_bytes());
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+
+    write_attribute(&mut header, "channels", "chlist", &chlist)?;
+    write_attribute(&mut header, "compression", "compression", &[0])?;
+    write_attribute(&mut header, "dataWindow", "box2i", &box2i(width, height))?;
+    write_attribute(&mut header, "displayWindow", "box2i", &box2i(width, height))?;
+    write_attribute(&mut header, "lineOrder", "lineOrder", &[0])?;
+    write_attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1.0f32.to_le_bytes(),
+    )?;
+    write_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+    write_attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1.0f32.to_le_bytes(),
+    )?;
+    header.push(0);
+
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(&header)?;
+
+    // Offset table, one uncompressed scanline per chunk.
+    let line_size = 4 * width as u64 * channels.len() as u64;
+    let first_chunk = header.len() as u64 + 8 * height as u64;
+
+    for y in 0..height as u64 {
+        w.write_all(&(first_chunk + y * (8 + line_size)).to_le_bytes())?;
+    }
+
+    for y in 0..height {
+        w.write_all(&(y as i32).to_le_bytes())?;
+        w.write_all(&(line_size as i32).to_le_bytes())?;
+
+        for channel in channels.iter() {
+            let row = &channel.values[(y * width) as usize..((y + 1) * width) as usize];
+
+            for v in row {
+                w.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+
+    w.flush()
+}
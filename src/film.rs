@@ -0,0 +1,485 @@
+extern crate nalgebra as na;
+use na::{Vector2, Vector3};
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+pub trait Filter: Send + Sync {
+    fn radius(&self) -> f64;
+
+    // `p` is the offset from the pixel center, in pixels.
+    fn evaluate(&self, p: &Vector2<f64>) -> f64;
+}
+
+pub struct BoxFilter {
+    pub radius: f64,
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, _: &Vector2<f64>) -> f64 {
+        1.0
+    }
+}
+
+pub struct TentFilter {
+    pub radius: f64,
+}
+
+impl Filter for TentFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Vector2<f64>) -> f64 {
+        (self.radius - p.x.abs()).max(0.0) * (self.radius - p.y.abs()).max(0.0)
+    }
+}
+
+pub struct GaussianFilter {
+    pub radius: f64,
+    pub alpha: f64,
+}
+
+impl GaussianFilter {
+    fn gaussian(&self, d: f64) -> f64 {
+        ((-self.alpha * d * d).exp() - (-self.alpha * self.radius * self.radius).exp()).max(0.0)
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Vector2<f64>) -> f64 {
+        self.gaussian(p.x) * self.gaussian(p.y)
+    }
+}
+
+pub struct MitchellFilter {
+    pub radius: f64,
+    pub b: f64,
+    pub c: f64,
+}
+
+impl MitchellFilter {
+    fn mitchell(&self, x: f64) -> f64 {
+        let x = (2.0 * x / self.radius).abs();
+        let (b, c) = (self.b, self.c);
+
+        if x > 2.0 {
+            0.0
+        } else if x > 1.0 {
+            ((-b - 6.0 * c) * x * x * x
+                + (6.0 * b + 30.0 * c) * x * x
+                + (-12.0 * b - 48.0 * c) * x
+                + (8.0 * b + 24.0 * c))
+                / 6.0
+        } else {
+            ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                + (6.0 - 2.0 * b))
+                / 6.0
+        }
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Vector2<f64>) -> f64 {
+        self.mitchell(p.x) * self.mitchell(p.y)
+    }
+}
+
+// Windowed sinc, `tau` is the number of sinc lobes kept by the window.
+pub struct LanczosFilter {
+    pub radius: f64,
+    pub tau: f64,
+}
+
+fn sinc(x: f64) -> f64 {
+    let x = x.abs();
+
+    if x < 1.0e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+impl LanczosFilter {
+    fn windowed_sinc(&self, x: f64) -> f64 {
+        if x.abs() > self.radius {
+            0.0
+        } else {
+            sinc(x) * sinc(x / self.tau)
+        }
+    }
+}
+
+impl Filter for LanczosFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Vector2<f64>) -> f64 {
+        self.windowed_sinc(p.x) * self.windowed_sinc(p.y)
+    }
+}
+
+// Arbitrary output variables of a camera path. The surface properties are
+// those of the first hit, `depth` is the ray distance and zero on a miss.
+// The light is split into direct (emission and light sampled at the first
+// hit) and indirect, and separately by the lobe of the first hit. Pixels
+// sum them like the radiance, except for the IDs which keep the first hit
+// seen by the pixel.
+#[derive(Clone, Copy)]
+pub struct Aovs {
+    pub albedo: Vector3<f64>,
+    pub normal: Vector3<f64>,
+    pub depth: f64,
+    pub position: Vector3<f64>,
+    pub material_id: Option<u32>,
+    pub group_id: Option<u32>,
+    pub direct: Vector3<f64>,
+    pub indirect: Vector3<f64>,
+    pub diffuse: Vector3<f64>,
+    pub specular: Vector3<f64>,
+}
+
+impl Aovs {
+    pub fn new() -> Aovs {
+        Aovs {
+            albedo: Vector3::zeros(),
+            normal: Vector3::zeros(),
+            depth: 0.0,
+            position: Vector3::zeros(),
+            material_id: None,
+            group_id: None,
+            direct: Vector3::zeros(),
+            indirect: Vector3::zeros(),
+            diffuse: Vector3::zeros(),
+            specular: Vector3::zeros(),
+        }
+    }
+
+    fn add(&mut self, other: &Aovs) {
+        self.albedo += other.albedo;
+        self.normal += other.normal;
+        self.depth += other.depth;
+        self.position += other.position;
+        self.material_id = self.material_id.or(other.material_id);
+        self.group_id = self.group_id.or(other.group_id);
+        self.direct += other.direct;
+        self.indirect += other.indirect;
+        self.diffuse += other.diffuse;
+        self.specular += other.specular;
+    }
+
+    fn scale(&self, s: f64) -> Aovs {
+        Aovs {
+            albedo: self.albedo * s,
+            normal: self.normal * s,
+            depth: self.depth * s,
+            position: self.position * s,
+            direct: self.direct * s,
+            indirect: self.indirect * s,
+            diffuse: self.diffuse * s,
+            specular: self.specular * s,
+            ..*self
+        }
+    }
+}
+
+impl Default for Aovs {
+    fn default() -> Aovs {
+        Aovs::new()
+    }
+}
+
+pub fn luminance(c: &Vector3<f64>) -> f64 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+// `samples`, `lum_sum` and `lum_sq_sum` only count the unfiltered samples
+// taken inside of the pixel, they estimate the variance of its mean.
+// `splat` sums the light reaching the pixel from paths started elsewhere,
+// see `Film::colors`.
+#[derive(Clone, Copy)]
+pub struct Pixel {
+    pub sum: Vector3<f64>,
+    pub weight: f64,
+    pub samples: u32,
+    pub lum_sum: f64,
+    pub lum_sq_sum: f64,
+    pub splat: Vector3<f64>,
+    pub aovs: Aovs,
+}
+
+impl Pixel {
+    fn new() -> Pixel {
+        Pixel {
+            sum: Vector3::zeros(),
+            weight: 0.0,
+            samples: 0,
+            lum_sum: 0.0,
+            lum_sq_sum: 0.0,
+            splat: Vector3::zeros(),
+            aovs: Aovs::new(),
+        }
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.samples > 0 {
+            self.lum_sum / self.samples as f64
+        } else {
+            0.0
+        }
+    }
+
+    pub fn variance(&self) -> f64 {
+        if self.samples < 2 {
+            return 0.0;
+        }
+
+        let n = self.samples as f64;
+        ((self.lum_sq_sum - self.lum_sum * self.lum_sum / n) / (n - 1.0)).max(0.0)
+    }
+
+    // Standard error of the mean relative to the mean. Dark pixels are
+    // measured against a floor so they don't get sampled forever.
+    pub fn relative_error(&self) -> f64 {
+        if self.samples < 2 {
+            return f64::INFINITY;
+        }
+
+        (self.variance() / self.samples as f64).sqrt() / self.mean().max(1.0e-2)
+    }
+
+    pub fn color(&self) -> Vector3<f64> {
+        if self.weight > 0.0 {
+            (self.sum / self.weight).map(|c| c.max(0.0))
+        } else {
+            Vector3::zeros()
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct TileBounds {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Pixel>,
+    pub filter: Arc<dyn Filter>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32, filter: Arc<dyn Filter>) -> Film {
+        Film {
+            width,
+            height,
+            pixels: vec![Pixel::new(); (width * height) as usize],
+            filter,
+        }
+    }
+
+    pub fn tiles(&self, size: u32) -> Vec<TileBounds> {
+        let mut tiles = vec![];
+
+        for y0 in (0..self.height).step_by(size as usize) {
+            for x0 in (0..self.width).step_by(size as usize) {
+                tiles.push(TileBounds {
+                    x0,
+                    y0,
+                    x1: (x0 + size).min(self.width),
+                    y1: (y0 + size).min(self.height),
+                });
+            }
+        }
+
+        tiles
+    }
+
+    // Tiles cover the pixels reached by the filter from samples inside of
+    // `bounds`, so they can be splatted into independently and merged later.
+    pub fn tile(&self, bounds: &TileBounds) -> FilmTile {
+        let r = self.filter.radius().ceil() as u32;
+
+        let tile_bounds = TileBounds {
+            x0: bounds.x0.saturating_sub(r),
+            y0: bounds.y0.saturating_sub(r),
+            x1: (bounds.x1 + r).min(self.width),
+            y1: (bounds.y1 + r).min(self.height),
+        };
+
+        let size = (tile_bounds.x1 - tile_bounds.x0) * (tile_bounds.y1 - tile_bounds.y0);
+
+        FilmTile {
+            bounds: tile_bounds,
+            pixels: vec![Pixel::new(); size as usize],
+            splats: vec![],
+            filter: self.filter.clone(),
+        }
+    }
+
+    pub fn merge_tile(&mut self, tile: FilmTile) {
+        let tile_width = tile.bounds.x1 - tile.bounds.x0;
+
+        for y in tile.bounds.y0..tile.bounds.y1 {
+            for x in tile.bounds.x0..tile.bounds.x1 {
+                let src = &tile.pixels
+                    [((y - tile.bounds.y0) * tile_width + (x - tile.bounds.x0)) as usize];
+                let dst = &mut self.pixels[(y * self.width + x) as usize];
+
+                dst.sum += src.sum;
+                dst.weight += src.weight;
+                dst.samples += src.samples;
+                dst.lum_sum += src.lum_sum;
+                dst.lum_sq_sum += src.lum_sq_sum;
+                dst.aovs.add(&src.aovs);
+            }
+        }
+
+        for (p, l) in tile.splats {
+            let (x, y) = (p.x as u32, p.y as u32);
+
+            if x < self.width && y < self.height {
+                self.pixels[(y * self.width + x) as usize].splat += l;
+            }
+        }
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> &Pixel {
+        &self.pixels[(y * self.width + x) as usize]
+    }
+
+    // Per pixel AOVs averaged over the samples of the pixel.
+    pub fn aovs(&self) -> Vec<Aovs> {
+        self.pixels
+            .iter()
+            .map(|p| Aovs {
+                normal: p
+                    .aovs
+                    .normal
+                    .try_normalize(1.0e-9)
+                    .unwrap_or_else(Vector3::zeros),
+                ..p.aovs.scale(1.0 / p.samples.max(1) as f64)
+            })
+            .collect()
+    }
+
+    // Splats are estimates of the whole image made by every sample, so
+    // they are divided by the number of samples per pixel on average.
+    pub fn colors(&self) -> Vec<Vector3<f64>> {
+        let samples: u64 = self.pixels.iter().map(|p| p.samples as u64).sum();
+        let scale = self.pixels.len() as f64 / samples.max(1) as f64;
+
+        self.pixels
+            .iter()
+            .map(|p| p.color() + p.splat * scale)
+            .collect()
+    }
+
+    pub fn to_image(&self) -> image::RgbImage {
+        to_image(self.width, self.height, &self.colors())
+    }
+
+    // Samples per pixel relative to `max_spp` as grey levels.
+    pub fn sample_map(&self, max_spp: u32) -> image::GrayImage {
+        let mut im = image::GrayImage::new(self.width, self.height);
+
+        for (x, y, pixel) in im.enumerate_pixels_mut() {
+            let n = self.get_pixel(x, y).samples as f64 / max_spp.max(1) as f64;
+            pixel[0] = (n.min(1.0) * 255.0).round() as u8;
+        }
+
+        im
+    }
+}
+
+pub fn to_image(width: u32, height: u32, colors: &[Vector3<f64>]) -> image::RgbImage {
+    let mut im = image::RgbImage::new(width, height);
+
+    for (x, y, pixel) in im.enumerate_pixels_mut() {
+        let c = colors[(y * width + x) as usize];
+
+        pixel[0] = (c[0].powf(1.0 / 2.2).min(1.0) * 255.0) as u8;
+        pixel[1] = (c[1].powf(1.0 / 2.2).min(1.0) * 255.0) as u8;
+        pixel[2] = (c[2].powf(1.0 / 2.2).min(1.0) * 255.0) as u8;
+    }
+
+    im
+}
+
+// `splats` can land anywhere on the film and are added to the pixel they
+// fall in when the tile is merged.
+pub struct FilmTile {
+    pub bounds: TileBounds,
+    pub pixels: Vec<Pixel>,
+    pub splats: Vec<(Vector2<f64>, Vector3<f64>)>,
+    filter: Arc<dyn Filter>,
+}
+
+impl FilmTile {
+    // `p` is in continuous film coordinates, pixel (i, j) covers
+    // [i, i + 1) x [j, j + 1).
+    pub fn add_sample(&mut self, p: &Vector2<f64>, l: &Vector3<f64>, aovs: &Aovs) {
+        let r = self.filter.radius();
+        let d = p - Vector2::repeat(0.5);
+
+        let x0 = ((d.x - r).ceil().max(self.bounds.x0 as f64)) as u32;
+        let y0 = ((d.y - r).ceil().max(self.bounds.y0 as f64)) as u32;
+        let x1 = ((d.x + r).floor() + 1.0)
+            .min(self.bounds.x1 as f64)
+            .max(0.0) as u32;
+        let y1 = ((d.y + r).floor() + 1.0)
+            .min(self.bounds.y1 as f64)
+            .max(0.0) as u32;
+
+        let tile_width = self.bounds.x1 - self.bounds.x0;
+
+        let (px, py) = (p.x.floor() as u32, p.y.floor() as u32);
+        let y = luminance(l);
+
+        let pixel =
+            &mut self.pixels[((py - self.bounds.y0) * tile_width + (px - self.bounds.x0)) as usize];
+        pixel.samples += 1;
+        pixel.lum_sum += y;
+        pixel.lum_sq_sum += y * y;
+        pixel.aovs.add(aovs);
+
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let w = self
+                    .filter
+                    .evaluate(&Vector2::new(x as f64 - d.x, y as f64 - d.y));
+
+                let pixel = &mut self.pixels
+                    [((y - self.bounds.y0) * tile_width + (x - self.bounds.x0)) as usize];
+
+                pixel.sum += l * w;
+                pixel.weight += w;
+            }
+        }
+    }
+
+    // Light reaching the film at `p` (in continuous film coordinates) from
+    // a path that didn't start at a camera sample.
+    pub fn add_splat(&mut self, p: &Vector2<f64>, l: &Vector3<f64>) {
+        self.splats.push((*p, *l));
+    }
+}
@@ -0,0 +1,292 @@
+extern crate nalgebra as na;
+use na::{Point3, Vector3};
+
+use crate::brdf::{BRDFInput, DiffuseBRDF, BRDF};
+use crate::debug::DebugMode;
+use crate::film::Aovs;
+use crate::light::Light;
+use crate::ray::Ray;
+use crate::sample;
+use crate::sampler::Sampler;
+use crate::scene::{AoSettings, Scene};
+use crate::stats;
+
+// Light sampled at `s`, split into the diffuse and specular lobe of `brdf`.
+// Delta BRDFs never reflect a sampled light.
+pub fn direct_light(
+    s: &sample::SampleRecord,
+    brdf: &dyn BRDF,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+) -> (Vector3<f64>, Vector3<f64>) {
+    let light = scene.get_light(sampler.get_1d());
+
+    let (lp, lpdf) = light.sample_point(&sampler.get_2d());
+
+    if brdf.delta() {
+        return (Vector3::zeros(), Vector3::zeros());
+    }
+
+    let mut sr = s.spawn(&(lp - s.o));
+    let lpo = lp - sr.origin;
+    sr.direction = lpo.normalize();
+
+    let mut diffuse = Vector3::<f64>::zeros();
+    let mut specular = Vector3::<f64>::zeros();
+
+    stats::count(|c| c.shadow_rays += 1);
+
+    if !scene.obj.occluded(&sr, lpo.norm()) {
+        let lp2 = s.m * lp;
+        let lp2s = lp2 - s.p;
+        let lv = lp2s.normalize();
+        let ld = lp2s.norm_squared();
+
+        let dot = s.n.dot(&lv).clamp(0.0, 1.0);
+
+        let (fd, fs) = brdf.lobes(&BRDFInput {
+            n: &s.n,
+            l: &lv,
+            v: &s.v,
+        });
+
+        let li = light.shade(&s.m, &s.o, &lv) / (lpdf * ld);
+
+        diffuse = (fd * dot).component_mul(&li);
+        specular = (fs * dot).component_mul(&li);
+    }
+
+    let n = scene.lights.len() as f64;
+
+    (diffuse * n, specular * n)
+}
+
+// Closest light `ray` reaches before anything in the scene, and where.
+// Lights aren't part of the geometry, so this is how paths leaving delta
+// BRDFs, which can't sample lights, find the light they reflect.
+pub fn reached_light<'a>(scene: &'a Scene, ray: &Ray) -> Option<(&'a dyn Light, Point3<f64>)> {
+    let (light, t) = scene
+        .lights
+        .iter()
+        .filter_map(|light| light.intersect(ray).map(|t| (light.as_ref(), t)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))?;
+
+    stats::count(|c| c.shadow_rays += 1);
+
+    if scene.obj.occluded(ray, t) {
+        None
+    } else {
+        Some((light, ray.origin + t * ray.direction))
+    }
+}
+
+// Scale bringing the largest component of `c` down to `max`.
+fn clamp_scale(c: &Vector3<f64>, max: Option<f64>) -> f64 {
+    match max {
+        Some(max) if c.max() > max => max / c.max(),
+        _ => 1.0,
+    }
+}
+
+pub fn radiance(
+    depth: i32,
+    mut ray: Ray,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+    aovs: &mut Aovs,
+) -> Vector3<f64> {
+    let mut color = Vector3::<f64>::zeros();
+    let mut b = Vector3::<f64>::repeat(1.0);
+
+    // Share of the throughput going through the diffuse lobe of the first
+    // hit, used to split the indirect light.
+    let mut diffuse_share = Vector3::<f64>::zeros();
+
+    // Near specular BRDFs are only roughened after the first rough bounce,
+    // so directly visible mirrors stay sharp.
+    let mut after_rough_bounce = false;
+
+    let mut vertices = 0;
+
+    // The clay debug mode replaces every material with a grey diffuse one.
+    let clay = scene
+        .settings
+        .debug
+        .as_ref()
+        .filter(|debug| debug.mode == DebugMode::Clay)
+        .map(|_| DiffuseBRDF {
+            color: Vector3::repeat(0.8),
+        });
+
+    for bounce in 0..depth {
+        // The camera ray is counted as a primary ray by the renderer.
+        if bounce > 0 {
+            stats::count(|c| c.secondary_rays += 1);
+        }
+
+        let record = match scene.obj.intersect(&ray) {
+            Some(record) => record,
+            None => break,
+        };
+
+        vertices += 1;
+
+        let s = sample::SampleRecord::new(&ray, &record);
+
+        let material = match &clay {
+            Some(clay) => clay as &dyn BRDF,
+            None => record.brdf,
+        };
+
+        let roughened = scene
+            .settings
+            .regularize
+            .and_then(|roughness| material.roughened(roughness));
+
+        let brdf = match &roughened {
+            Some(roughened) if after_rough_bounce => roughened.as_ref(),
+            _ => material,
+        };
+
+        after_rough_bounce |= roughened.is_none();
+
+        let (ld, ls) = direct_light(&s, brdf, scene, sampler);
+        let lc = ld + ls;
+
+        let (l, pdf) = brdf.p(&s.v, &sampler.get_2d());
+        let e = brdf.e();
+
+        let f = brdf.f(&BRDFInput {
+            n: &s.n,
+            l: &l,
+            v: &s.v,
+        });
+
+        ray = s.spawn(&s.m.inverse_transform_vector(&l));
+
+        let weight = if brdf.delta() {
+            f
+        } else {
+            (f / pdf) * s.n.dot(&l)
+        };
+
+        // Light reflected by delta BRDFs, `direct_light` leaves it out.
+        let reached = if brdf.delta() {
+            reached_light(scene, &ray)
+        } else {
+            None
+        };
+
+        let lr = match reached {
+            Some((light, p)) => weight.component_mul(&light.radiance(&p, &-ray.direction)),
+            None => Vector3::zeros(),
+        };
+
+        let mut contribution = (e + lc + lr).component_mul(&b);
+
+        let clamp = if bounce == 0 {
+            scene.settings.clamp_direct
+        } else {
+            scene.settings.clamp_indirect
+        };
+
+        let k = clamp_scale(&contribution, clamp);
+        contribution *= k;
+
+        color += contribution;
+
+        if bounce == 0 {
+            let (fd, _) = brdf.lobes(&BRDFInput {
+                n: &s.n,
+                l: &l,
+                v: &s.v,
+            });
+
+            diffuse_share = Vector3::from_fn(|k, _| if f[k] > 0.0 { fd[k] / f[k] } else { 0.0 });
+
+            *aovs = Aovs {
+                albedo: brdf.albedo(),
+                normal: record.normal,
+                depth: record.t,
+                position: s.o.coords,
+                material_id: Some(record.material_id),
+                group_id: Some(record.group_id),
+                direct: contribution,
+                indirect: Vector3::zeros(),
+                diffuse: ld * k,
+                specular: (ls + lr) * k,
+            };
+        } else {
+            let diffuse = contribution.component_mul(&diffuse_share);
+
+            aovs.indirect += contribution;
+            aovs.diffuse += diffuse;
+            aovs.specular += contribution - diffuse;
+        }
+
+        b = b.component_mul(&weight);
+    }
+
+    stats::count(|c| c.path_vertices += vertices);
+
+    color
+}
+
+// Fraction of `samples` cosine distributed rays from `s` that get `distance`
+// far without hitting anything.
+pub fn unoccluded(
+    s: &sample::SampleRecord,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+    distance: f64,
+    samples: u32,
+) -> f64 {
+    let mut open = 0;
+
+    for _ in 0..samples {
+        let l = sample::cosine_hemisphere(&sampler.get_2d());
+        let ray = s.spawn(&s.m.inverse_transform_vector(&l));
+
+        stats::count(|c| c.shadow_rays += 1);
+
+        if !scene.obj.occluded(&ray, distance) {
+            open += 1;
+        }
+    }
+
+    open as f64 / samples as f64
+}
+
+// Ambient occlusion at the first hit as a grey level, black where nothing is
+// hit. The AOVs see it as the direct diffuse light of a white surface.
+pub fn ambient_occlusion(
+    settings: &AoSettings,
+    ray: Ray,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+    aovs: &mut Aovs,
+) -> Vector3<f64> {
+    let record = match scene.obj.intersect(&ray) {
+        Some(record) => record,
+        None => return Vector3::zeros(),
+    };
+
+    let s = sample::SampleRecord::new(&ray, &record);
+    let distance = settings.distance.unwrap_or_else(|| scene.size());
+    let c = Vector3::repeat(unoccluded(&s, scene, sampler, distance, settings.samples));
+
+    *aovs = Aovs {
+        albedo: Vector3::repeat(1.0),
+        normal: record.normal,
+        depth: record.t,
+        position: s.o.coords,
+        material_id: Some(record.material_id),
+        group_id: Some(record.group_id),
+        direct: c,
+        indirect: Vector3::zeros(),
+        diffuse: c,
+        specular: Vector3::zeros(),
+    };
+
+    c
+}
@@ -1,17 +1,41 @@
 extern crate nalgebra as na;
-use na::{Isometry3, Point3, Vector3};
+use na::{Isometry3, Point3, Vector2, Vector3};
 
 // use crate::brdf::{BRDFInput, BRDF};
 
+use crate::ray::Ray;
 use crate::sample;
 
-use rand::random;
 use std::f64::consts::PI;
 
-pub trait Light {
-    fn sample_point(&self) -> (Point3<f64>, f64);
+pub trait Light: Send + Sync {
+    // Point on the light and the pdf of picking it per unit area.
+    fn sample_point(&self, u: &Vector2<f64>) -> (Point3<f64>, f64);
 
     fn shade(&self, m: &Isometry3<f64>, p: &Point3<f64>, v: &Vector3<f64>) -> Vector3<f64>;
+
+    fn normal(&self, p: &Point3<f64>) -> Vector3<f64>;
+
+    // Radiance leaving `p` on the light in the world space direction `w`.
+    fn radiance(&self, p: &Point3<f64>, w: &Vector3<f64>) -> Vector3<f64>;
+
+    // Ray leaving the light, for light paths.
+    fn sample_emission(&self, u1: &Vector2<f64>, u2: &Vector2<f64>) -> EmissionSample;
+
+    // Area and solid angle pdfs of `sample_emission` leaving `p` along `w`.
+    fn emission_pdf(&self, p: &Point3<f64>, w: &Vector3<f64>) -> (f64, f64);
+
+    // Distance along `ray` to the light, if it's hit in `[t_min, t_max]`.
+    fn intersect(&self, ray: &Ray) -> Option<f64>;
+}
+
+pub struct EmissionSample {
+    pub point: Point3<f64>,
+    pub normal: Vector3<f64>,
+    pub direction: Vector3<f64>,
+    pub radiance: Vector3<f64>,
+    pub pdf_pos: f64,
+    pub pdf_dir: f64,
 }
 
 pub struct DiskLight {
@@ -22,24 +46,83 @@ pub struct DiskLight {
     pub normal: Vector3<f64>,
 }
 
+impl DiskLight {
+    fn area(&self) -> f64 {
+        PI * self.radius * self.radius
+    }
+}
+
 impl Light for DiskLight {
-    fn sample_point(&self) -> (Point3<f64>, f64) {
+    // Uniform over the area of the disk.
+    fn sample_point(&self, u: &Vector2<f64>) -> (Point3<f64>, f64) {
         let sm = sample::onb(&self.pos, &self.normal);
 
-        let theta = 2.0 * PI * random::<f64>();
-        let r = self.radius * random::<f64>();
+        let theta = 2.0 * PI * u.x;
+        let r = self.radius * u.y.sqrt();
 
         (
-            sm.inverse_transform_point(&Point3::<f64>::new(
-                r.sqrt() * theta.cos(),
-                r.sqrt() * theta.sin(),
-                0.0,
-            )),
-            1.0 / std::f64::consts::PI * self.radius * self.radius,
+            sm.inverse_transform_point(&Point3::<f64>::new(r * theta.cos(), r * theta.sin(), 0.0)),
+            1.0 / self.area(),
         )
     }
 
     fn shade(&self, m: &Isometry3<f64>, _: &Point3<f64>, v: &Vector3<f64>) -> Vector3<f64> {
-        (m * self.normal).dot(&-v).max(0.0).min(1.0) * self.color * self.power
+        (m * self.normal).dot(&-v).clamp(0.0, 1.0) * self.color * self.power
+    }
+
+    fn normal(&self, _: &Point3<f64>) -> Vector3<f64> {
+        self.normal
+    }
+
+    // Disks only emit on the side of their normal.
+    fn radiance(&self, _: &Point3<f64>, w: &Vector3<f64>) -> Vector3<f64> {
+        if self.normal.dot(w) > 0.0 {
+            self.color * self.power
+        } else {
+            Vector3::zeros()
+        }
+    }
+
+    fn sample_emission(&self, u1: &Vector2<f64>, u2: &Vector2<f64>) -> EmissionSample {
+        let (point, pdf_pos) = self.sample_point(u1);
+
+        let local = sample::cosine_hemisphere(u2);
+        let direction = sample::onb(&self.pos, &self.normal).inverse_transform_vector(&local);
+
+        EmissionSample {
+            point,
+            normal: self.normal,
+            direction,
+            radiance: self.color * self.power,
+            pdf_pos,
+            pdf_dir: local.z / PI,
+        }
+    }
+
+    fn emission_pdf(&self, _: &Point3<f64>, w: &Vector3<f64>) -> (f64, f64) {
+        (1.0 / self.area(), self.normal.dot(w).max(0.0) / PI)
+    }
+
+    // Both sides are hit, `radiance` tells which one emits.
+    fn intersect(&self, ray: &Ray) -> Option<f64> {
+        let den = self.normal.dot(&ray.direction);
+
+        if den == 0.0 {
+            return None;
+        }
+
+        let t = (self.pos - ray.origin).dot(&self.normal) / den;
+
+        if t < ray.t_min || t > ray.t_max {
+            return None;
+        }
+
+        let d = ray.origin + t * ray.direction - self.pos;
+
+        if d.norm_squared() <= self.radius * self.radius {
+            Some(t)
+        } else {
+            None
+        }
     }
 }
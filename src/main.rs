@@ -1,18 +1,14 @@
+#![allow(clippy::upper_case_acronyms)]
+
 extern crate image;
 
 extern crate nalgebra as na;
-use na::Point3;
-use na::Vector2;
-use na::Vector3;
 
 pub mod camera;
-use crate::camera::Camera;
 
 mod brdf;
-use crate::brdf::{BRDFInput, BRDF};
 
 pub mod object;
-use crate::object::*;
 
 pub mod primitive;
 
@@ -24,8 +20,6 @@ pub mod mesh;
 
 use std::time::Instant;
 
-use crate::ray::Ray;
-
 #[macro_use]
 extern crate lazy_static;
 
@@ -33,187 +27,130 @@ pub mod sample;
 
 pub mod light;
 
-use crate::light::*;
-
 pub mod bvh;
 
-struct Scene {
-    obj: Box<dyn Intersect>,
-    lights: Vec<Box<dyn Light>>,
-}
+pub mod motion;
 
-use rand::random;
+pub mod film;
 
-impl Scene {
-    fn get_light(&self) -> &Box<dyn Light> {
-        let i = (random::<f64>() * self.lights.len() as f64).floor() as usize;
-        &self.lights[i]
-    }
-}
+pub mod sampler;
 
-fn direct_light(s: &sample::SampleRecord, brdf: &Box<dyn BRDF>, scene: &Scene) -> Vector3<f64> {
-    let light = scene.get_light();
+pub mod scene;
 
-    let (lp, lpdf) = light.sample_point();
+pub mod integrator;
 
-    let lpo = lp - s.o;
+pub mod bdpt;
 
-    let sr = Ray {
-        origin: s.o + s.on * 0.0000000001,
-        direction: lpo.normalize(),
-    };
+pub mod render;
 
-    let mut direct = Vector3::<f64>::zeros();
+pub mod checkpoint;
 
-    let res = scene.obj.intersect(&sr);
+pub mod denoise;
 
-    if res.is_none() || res.unwrap().t > lpo.norm() {
-        let lp2 = s.m * lp;
-        let lp2s = lp2 - s.p;
-        let lv = lp2s.normalize();
-        let ld = lp2s.norm_squared();
+pub mod exr;
 
-        let dot = s.n.dot(&lv).min(1.0).max(0.0);
+pub mod aov;
 
-        let lf = brdf.f(&BRDFInput {
-            n: &s.n,
-            l: &lv,
-            v: &s.v,
-        });
+pub mod binary;
 
-        direct = (lf * dot).component_mul(&(light.shade(&s.m, &s.o, &lv) / (lpdf * ld)));
-    }
+pub mod bvh_cache;
 
-    direct * scene.lights.len() as f64
-}
+pub mod wide_bvh;
 
-fn radiance(depth: i32, mut ray: Ray, scene: &Scene) -> Vector3<f64> {
-    let mut color = Vector3::<f64>::zeros();
-    let mut b = Vector3::<f64>::repeat(1.0);
+pub mod stats;
 
-    for _ in 0..depth {
-        if let Some(record) = scene.obj.intersect(&ray) {
-            let s = sample::SampleRecord::new(&ray, &record);
+pub mod debug;
 
-            let (l, pdf) = record.brdf.p(&s.v);
-            let e = record.brdf.e();
+pub mod bake;
 
-            let f = record.brdf.f(&BRDFInput {
-                n: &s.n,
-                l: &l,
-                v: &s.v,
-            });
+fn main() {
+    let mut scene_path = "scenes/final.json".to_owned();
+    let mut resume = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--resume" => resume = Some(args.next().expect("--resume needs a checkpoint path")),
+            _ => scene_path = arg,
+        }
+    }
+
+    let mut scene = scene::load_scene(&scene_path).unwrap();
+
+    if resume.is_some() && scene.settings.frames > 1 {
+        panic!("Animated scenes can't be resumed");
+    }
 
-            let lc = direct_light(&s, &record.brdf, &scene);
+    render::install_interrupt_handler();
 
-            ray = Ray {
-                origin: s.o + l * 0.0000000001,
-                direction: s.m.inverse_transform_vector(&l),
-            };
+    for frame in 0..scene.settings.frames {
+        if frame > 0 {
+            if render::interrupted() {
+                break;
+            }
 
-            color += (e + lc).component_mul(&b);
-            b = b.component_mul(&((f / pdf) * s.n.dot(&l)));
+            scene.set_frame(frame).unwrap();
         }
-    }
 
-    color
+        let film = match resume.take() {
+            Some(path) => checkpoint::load(&path, &scene).unwrap(),
+            None => film::Film::new(
+                scene.settings.width,
+                scene.settings.height,
+                scene.filter.clone(),
+            ),
+        };
+
+        render_frame(&scene, film, frame);
+    }
 }
 
-fn main() {
-    let width = 800;
-    let height = 600;
-
-    let mut im = image::RgbImage::new(width, height);
-    let (im_width, im_height) = im.dimensions();
-
-    let camera = Camera::new(
-        &Vector3::new(0.000000001, 3.2891, 6.673).into(),
-        &-(Vector3::new(0.000000001, 3.2891, 6.673) - Vector3::new(0.0, 0.87, 1.8)).normalize(),
-        Vector2::<u32>::new(width, height),
-        45.0,
-    );
-
-    // let camera = Camera::new(
-    //     &Vector3::new(0.000000001, 0.000000001, 7.0001).into(),
-    //     &-Vector3::new(0.000000001, 0.000000001, 7.0001).normalize(),
-    //     Vector2::<u32>::new(width, height),
-    //     33.3,
-    // );
-
-    //  let camera = Camera::new(
-    //     &Vector3::repeat(1.0).into(),
-    //     &-Vector3::new(1.0, 1.0, 1.0).normalize(),
-    //     Vector2::<u32>::new(width, height),
-    //     45.5,
-    // );
-
-    let scene = Scene {
-        obj: Box::new(mesh::load_model_bvh("tests/final.obj").unwrap()),
-        lights: vec![
-            Box::new(DiskLight {
-                pos: Point3::<f64>::new(0.0, 4.5, 0.0),
-                color: Vector3::<f64>::new(1.0, 1.0, 1.0),
-                power: 10.7,
-                radius: 1.8,
-                normal: (Point3::origin() - Point3::<f64>::new(0.0, 4.0, 0.0)).normalize(),
-            }),
-            // Box::new(DiskLight {
-            //     pos: Point3::<f64>::new(0.0, 1.0, 4.0),
-            //     color: Vector3::<f64>::new(1.0, 1.0, 1.0),
-            //     power: 1.6,
-            //     radius: 0.5,
-            //     normal: (Point3::origin() - Point3::<f64>::new(0.0, 1.0, 4.0)).normalize(),
-            // }),
-        ],
-    };
-
-    let spp = 256;
-
-    use indicatif::{ProgressBar, ProgressStyle};
-
-    let pb = ProgressBar::new((im_width * im_height) as u64);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed}] [{bar:40.cyan/blue}] {msg:.blue} ({eta:.red})")
-            .progress_chars("=> "),
-    );
-
-    let w_string_len = width.to_string().len();
-    let h_string_len = height.to_string().len();
+fn render_frame(scene: &scene::Scene, film: film::Film, frame: u32) {
+    let settings = &scene.settings;
 
     let start = Instant::now();
 
-    for i in 0..im_width {
-        for j in 0..im_height {
-            let mut c = Vector3::<f64>::repeat(0.0);
+    let film = render::render(scene, film);
+
+    let render_time = start.elapsed();
+
+    println!(" ");
+    println!("Execution time: {:?}", render_time);
+
+    if settings.profile.is_some() {
+        stats::print(render_time);
+    }
+
+    render::write_snapshot(&film, &settings.frame_path(&settings.output, frame));
 
-            for _ in 0..spp {
-                let ray = camera.get_ray(i, j);
-                c += radiance(3, ray, &scene);
+    if let Some(aovs) = &settings.aovs {
+        let aovs = scene::AovSettings {
+            aovs: aovs.aovs.clone(),
+            output: settings.frame_path(&aovs.output, frame),
+        };
+
+        if let Err(e) = aov::write_aovs(&film, &aovs) {
+            println!("Failed to write AOVs {}: {}", aovs.output, e);
+        }
+    }
+
+    // The bake is written once, from the geometry of the first frame.
+    if let Some(ao) = settings.ao.as_ref().filter(|_| frame == 0) {
+        if let Some(bake) = &ao.bake {
+            let distance = ao.distance.unwrap_or_else(|| scene.size());
+
+            if let Err(e) = bake::bake_ao(scene, bake, distance) {
+                println!("Failed to bake ambient occlusion {}: {}", bake.output, e);
             }
-            c /= spp as f64;
-
-            let pixel = im.get_pixel_mut(i, j);
-
-            pixel[0] = (c[0].powf(1.0/2.2).min(1.0) * 255.0) as u8;
-            pixel[1] = (c[1].powf(1.0/2.2).min(1.0) * 255.0) as u8;
-            pixel[2] = (c[2].powf(1.0/2.2).min(1.0) * 255.0) as u8;
-
-            pb.set_message(&format!(
-                "W:[{:w$}, {}] H:[{:h$}, {}]",
-                i,
-                im_width,
-                j,
-                im_height,
-                w = w_string_len,
-                h = h_string_len
-            ));
-            pb.inc(1);
         }
     }
 
-    println!(" ");
-    println!("Execution time: {:?}", start.elapsed());
+    if let Some(denoise) = &settings.denoise {
+        let colors = denoise::denoise(&film, denoise);
+        let path = settings.frame_path(&denoise.output, frame);
 
-    im.save("output.png").unwrap();
+        if let Err(e) = film::to_image(film.width, film.height, &colors).save(&path) {
+            println!("Failed to write denoised image {}: {}", path, e);
+        }
+    }
 }
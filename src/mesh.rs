@@ -6,19 +6,38 @@ use serde_json::json;
 
 use na::Vector3;
 
+use std::collections::HashMap;
 use std::error::Error;
+use std::sync::Arc;
 
 use crate::object;
-use crate::primitive::{AggregatePrimitive, Triangle, Vertex};
+use crate::primitive::{
+    AggregatePrimitive, IndexedMesh, MeshTriangle, Primitive, Triangle, Vertex,
+};
+use crate::ray::Ray;
 
 use crate::brdf::*;
 
-use crate::bvh;
+use crate::bvh::{self, Bounds};
+use crate::bvh_cache;
+use crate::wide_bvh::{self, WideTree};
 
 type Mesh = object::Object<AggregatePrimitive<Triangle>>;
 type Model = object::AggregateObject;
 
-type BVHMesh = object::Object<bvh::Tree>;
+type BVHMesh = object::Object<bvh::Tree<Triangle>>;
+
+// Groups sharing the same material description share an ID, the index of
+// the first group using it.
+fn material_id(meta_data: &serde_json::Value, name: &str) -> u32 {
+    let groups = meta_data["groups"].as_array().unwrap();
+    let material = &groups.iter().find(|x| x["name"] == json!(name)).unwrap()["material"];
+
+    groups
+        .iter()
+        .position(|x| x["material"] == *material)
+        .unwrap() as u32
+}
 
 fn create_material(meta_data: &serde_json::Value, name: &str) -> Box<dyn BRDF> {
     let data = &meta_data["groups"]
@@ -28,6 +47,10 @@ fn create_material(meta_data: &serde_json::Value, name: &str) -> Box<dyn BRDF> {
         .find(|x| x["name"] == json!(name))
         .unwrap()["material"];
 
+    read_material(data)
+}
+
+pub fn read_material(data: &serde_json::Value) -> Box<dyn BRDF> {
     match data["name"].as_str().unwrap() {
         "diffuse" => {
             let color = Vector3::<f64>::new(
@@ -37,7 +60,7 @@ fn create_material(meta_data: &serde_json::Value, name: &str) -> Box<dyn BRDF> {
             );
 
             Box::new(DiffuseBRDF { color })
-        },
+        }
 
         "mirror" => {
             let color = Vector3::<f64>::new(
@@ -47,7 +70,7 @@ fn create_material(meta_data: &serde_json::Value, name: &str) -> Box<dyn BRDF> {
             );
 
             Box::new(MirrorBRDF { color })
-        },
+        }
 
         "emissive" => {
             let color = Vector3::<f64>::new(
@@ -59,7 +82,7 @@ fn create_material(meta_data: &serde_json::Value, name: &str) -> Box<dyn BRDF> {
             let power = data["power"].as_f64().unwrap();
 
             Box::new(EmissiveBRDF { color, power })
-        },
+        }
 
         "microfacet" => {
             let albedo = Vector3::<f64>::new(
@@ -78,9 +101,13 @@ fn create_material(meta_data: &serde_json::Value, name: &str) -> Box<dyn BRDF> {
 
             let specular = data["specular"].as_f64().unwrap();
 
-            Box::new(MicrofacetBRDF { albedo, f0, roughness, specular })
-        },
-
+            Box::new(MicrofacetBRDF {
+                albedo,
+                f0,
+                roughness,
+                specular,
+            })
+        }
 
         _ => Box::new(DiffuseBRDF {
             color: Vector3::repeat(1.0),
@@ -133,7 +160,7 @@ fn load_mesh_group(
             }
         }
 
-        for (pos, nrm) in v_pos.into_iter().zip(v_nrm.into_iter()) {
+        for (pos, nrm) in v_pos.into_iter().zip(v_nrm) {
             vert.push(Vertex { pos, nrm });
         }
 
@@ -143,19 +170,100 @@ fn load_mesh_group(
 
     let mut mesh = Mesh::new(aggregate);
     mesh.brdf = create_material(meta_data, &group.name);
+    mesh.material_id = material_id(meta_data, &group.name);
+    mesh.group_id = index as u32;
 
     Ok(mesh)
 }
 
+// Polygon corners with the same position, texture coordinates and normal
+// share a vertex. Like in `load_mesh_group`, once a polygon without normals
+// comes up it and the following polygons of the group get face normals, on
+// vertices of their own. Texture coordinates are only kept if every corner
+// of the group has them.
+fn load_indexed_group(obj_mesh: &obj::Obj<obj::SimplePolygon>, index: usize) -> IndexedMesh {
+    let group = &obj_mesh.objects[0].groups[index];
+
+    let mut mesh = IndexedMesh {
+        positions: vec![],
+        normals: vec![],
+        uvs: vec![],
+        indices: vec![],
+    };
+
+    let has_uv = group
+        .polys
+        .iter()
+        .all(|poly| poly[..3].iter().all(|c| c.1.is_some()));
+
+    let mut shared = HashMap::new();
+    let mut has_normal = true;
+
+    for poly in group.polys.iter() {
+        let corners = &poly[..3];
+
+        if corners.iter().any(|c| c.2.is_none()) {
+            has_normal = false;
+        }
+
+        let mut triangle = [0; 3];
+
+        if has_normal {
+            for (k, obj::IndexTuple(pos_index, tex_index, nrm_index)) in corners.iter().enumerate()
+            {
+                let nrm_index = nrm_index.unwrap();
+                let tex_index = tex_index.filter(|_| has_uv);
+
+                triangle[k] = *shared
+                    .entry((*pos_index, tex_index, nrm_index))
+                    .or_insert_with(|| {
+                        mesh.positions.push(obj_mesh.position[*pos_index]);
+                        mesh.normals.push(obj_mesh.normal[nrm_index]);
+
+                        if let Some(tex_index) = tex_index {
+                            mesh.uvs.push(obj_mesh.texture[tex_index]);
+                        }
+
+                        mesh.positions.len() as u32 - 1
+                    });
+            }
+        } else {
+            let pos: Vec<Vector3<f64>> = corners
+                .iter()
+                .map(|c| obj_mesh.position[c.0].into())
+                .map(|p: Vector3<f32>| na::convert(p))
+                .collect();
+
+            let nrm = (pos[1] - pos[0]).cross(&(pos[2] - pos[0])).normalize();
+
+            for (k, c) in corners.iter().enumerate() {
+                mesh.positions.push(obj_mesh.position[c.0]);
+                mesh.normals
+                    .push([nrm.x as f32, nrm.y as f32, nrm.z as f32]);
+
+                if has_uv {
+                    mesh.uvs.push(obj_mesh.texture[c.1.unwrap()]);
+                }
+
+                triangle[k] = mesh.positions.len() as u32 - 1;
+            }
+        }
+
+        mesh.indices.push(triangle);
+    }
+
+    mesh
+}
+
 pub fn load_mesh(path: &str) -> Result<Mesh, Box<dyn Error>> {
     use std::path::Path;
-    let obj_mesh = obj::Obj::<obj::SimplePolygon>::load(&Path::new(path))?;
+    let obj_mesh = obj::Obj::<obj::SimplePolygon>::load(Path::new(path))?;
 
-    if obj_mesh.objects.len() < 1 {
+    if obj_mesh.objects.is_empty() {
         return Err("Failed to load obj, file needs to \
                     have at least one object"
             .into());
-    } else if obj_mesh.objects[0].groups.len() < 1 {
+    } else if obj_mesh.objects[0].groups.is_empty() {
         return Err("Failed to load obj, object needs to \
                     have at least one group"
             .into());
@@ -170,13 +278,13 @@ pub fn load_mesh(path: &str) -> Result<Mesh, Box<dyn Error>> {
 
 pub fn load_model(path: &str) -> Result<Model, Box<dyn Error>> {
     use std::path::Path;
-    let obj_mesh = obj::Obj::<obj::SimplePolygon>::load(&Path::new(path))?;
+    let obj_mesh = obj::Obj::<obj::SimplePolygon>::load(Path::new(path))?;
 
-    if obj_mesh.objects.len() < 1 {
+    if obj_mesh.objects.is_empty() {
         return Err("Failed to load obj, file needs to \
                     have at least one object"
             .into());
-    } else if obj_mesh.objects[0].groups.len() < 1 {
+    } else if obj_mesh.objects[0].groups.is_empty() {
         return Err("Failed to load obj, object needs to \
                     have at least one group"
             .into());
@@ -203,50 +311,260 @@ pub fn load_mesh_bvh(path: &str) -> Result<BVHMesh, Box<dyn Error>> {
     Ok(BVHMesh {
         primitive: bvh::Tree::new(mesh.primitive),
         brdf: mesh.brdf,
+        material_id: mesh.material_id,
+        group_id: mesh.group_id,
+    })
+}
+
+// Trees of the groups of a model, from the cache if there is one.
+fn load_model_trees(
+    path: &str,
+    settings: &bvh::BuildSettings,
+) -> Result<bvh_cache::Groups, Box<dyn Error>> {
+    let cache_path = path.to_owned() + ".bvh";
+    let key = if settings.cache {
+        Some(bvh_cache::key(&std::fs::read(path)?, settings))
+    } else {
+        None
+    };
+
+    let trees = match key.map(|key| bvh_cache::load(&cache_path, key)) {
+        Some(Ok(trees)) => trees,
+        _ => {
+            let trees = build_model_trees(path, settings)?;
+
+            if let Some(key) = key {
+                if let Err(e) = bvh_cache::save(&cache_path, key, &trees) {
+                    println!("Failed to write BVH cache {}: {}", cache_path, e);
+                }
+            }
+
+            trees
+        }
+    };
+
+    Ok(trees)
+}
+
+// One tree per group, under an object tree over the groups.
+pub fn load_model_bvh(
+    path: &str,
+    settings: &bvh::BuildSettings,
+) -> Result<bvh::ObjectTree, Box<dyn Error>> {
+    let meta_path = path.to_owned() + ".json";
+
+    let meta_data = serde_json::from_str(&std::fs::read_to_string(meta_path)?)?;
+
+    let mut groups: Vec<Box<dyn object::Intersect>> = vec![];
+
+    for (index, (name, tree)) in load_model_trees(path, settings)?.into_iter().enumerate() {
+        if settings.stats {
+            tree.stats().print(&format!("{} {}", path, name));
+        }
+
+        if settings.benchmark {
+            wide_bvh::benchmark(&format!("{} {}", path, name), &tree);
+        }
+
+        let group: Box<dyn object::Intersect> = match settings.width {
+            4 => Box::new(group_object(
+                WideTree::<4>::new(&tree),
+                &meta_data,
+                &name,
+                index,
+            )),
+            8 => Box::new(group_object(
+                WideTree::<8>::new(&tree),
+                &meta_data,
+                &name,
+                index,
+            )),
+            _ => Box::new(group_object(tree, &meta_data, &name, index)),
+        };
+
+        groups.push(group);
+    }
+
+    Ok(bvh::ObjectTree::new(groups, settings))
+}
+
+// Model whose vertices move from frame to frame. `paths` are the model
+// itself and an OBJ file with the same groups and triangles for every later
+// frame, the last one is held after them. The trees of the groups are
+// refitted to each frame, they always stay binary.
+pub struct AnimatedModel {
+    paths: Vec<String>,
+    groups: Vec<object::Object<bvh::Tree<MeshTriangle>>>,
+    settings: bvh::BuildSettings,
+    frame: usize,
+}
+
+pub fn load_animated_model(
+    path: &str,
+    frames: Vec<String>,
+    settings: &bvh::BuildSettings,
+) -> Result<AnimatedModel, Box<dyn Error>> {
+    let meta_path = path.to_owned() + ".json";
+
+    let meta_data = serde_json::from_str(&std::fs::read_to_string(meta_path)?)?;
+
+    let mut groups = vec![];
+
+    for (index, (name, tree)) in load_model_trees(path, settings)?.into_iter().enumerate() {
+        if settings.stats {
+            tree.stats().print(&format!("{} {}", path, name));
+        }
+
+        groups.push(group_object(tree, &meta_data, &name, index));
+    }
+
+    Ok(AnimatedModel {
+        paths: std::iter::once(path.to_owned()).chain(frames).collect(),
+        groups,
+        settings: settings.clone(),
+        frame: 0,
     })
 }
 
-pub fn load_model_bvh(path: &str) -> Result<Model, Box<dyn Error>> {
+impl object::Intersect for AnimatedModel {
+    fn intersect(&self, ray: &Ray) -> Option<object::IntersectionRecord<'_>> {
+        let mut closest: Option<object::IntersectionRecord> = None;
+
+        for group in self.groups.iter() {
+            let ray = Ray {
+                t_max: closest.as_ref().map_or(ray.t_max, |c| c.t),
+                ..*ray
+            };
+
+            if let Some(record) = group.intersect(&ray) {
+                closest = Some(record);
+            }
+        }
+
+        closest
+    }
+
+    fn occluded(&self, ray: &Ray, t_max: f64) -> bool {
+        self.groups.iter().any(|g| g.occluded(ray, t_max))
+    }
+
+    fn bounds(&self) -> Option<Bounds> {
+        self.groups
+            .iter()
+            .filter_map(|g| g.bounds())
+            .reduce(|a, b| a.union(&b))
+    }
+
+    fn set_frame(&mut self, frame: usize) -> Result<(), Box<dyn Error>> {
+        let frame = frame.min(self.paths.len() - 1);
+
+        if frame == self.frame {
+            return Ok(());
+        }
+
+        let path = &self.paths[frame];
+        let meshes = load_indexed_groups(path)?;
+
+        if meshes.len() != self.groups.len() {
+            return Err(format!("{} doesn't have the groups of {}", path, self.paths[0]).into());
+        }
+
+        for (group, (name, mesh)) in self.groups.iter_mut().zip(meshes) {
+            let rebuilt = group
+                .primitive
+                .update_mesh(mesh, &self.settings)
+                .map_err(|e| format!("{} {}: {}", path, name, e))?;
+
+            if rebuilt && self.settings.stats {
+                println!("{} {}: rebuilt after refitting", path, name);
+            }
+        }
+
+        self.frame = frame;
+        Ok(())
+    }
+
+    fn animated(&self) -> bool {
+        self.paths.len() > 1
+    }
+}
+
+fn group_object<T: Primitive>(
+    primitive: T,
+    meta_data: &serde_json::Value,
+    name: &str,
+    index: usize,
+) -> object::Object<T> {
+    object::Object {
+        primitive,
+        brdf: create_material(meta_data, name),
+        material_id: material_id(meta_data, name),
+        group_id: index as u32,
+    }
+}
+
+// Indexed mesh of every group with its name.
+pub fn load_indexed_groups(path: &str) -> Result<Vec<(String, IndexedMesh)>, Box<dyn Error>> {
     use std::path::Path;
-    let obj_mesh = obj::Obj::<obj::SimplePolygon>::load(&Path::new(path))?;
+    let obj_mesh = obj::Obj::<obj::SimplePolygon>::load(Path::new(path))?;
 
-    if obj_mesh.objects.len() < 1 {
+    if obj_mesh.objects.is_empty() {
         return Err("Failed to load obj, file needs to \
                     have at least one object"
             .into());
-    } else if obj_mesh.objects[0].groups.len() < 1 {
+    } else if obj_mesh.objects[0].groups.is_empty() {
         return Err("Failed to load obj, object needs to \
                     have at least one group"
             .into());
     }
 
-    let meta_path = path.to_owned() + ".json";
-
-    let meta_data = serde_json::from_str(&std::fs::read_to_string(meta_path)?)?;
-
-    let mut model = Model::new();
+    Ok(obj_mesh.objects[0]
+        .groups
+        .iter()
+        .enumerate()
+        .map(|(index, group)| (group.name.clone(), load_indexed_group(&obj_mesh, index)))
+        .collect())
+}
 
-    for (index, _) in obj_mesh.objects[0].groups.iter().enumerate() {
-        let mesh = load_mesh_group(&obj_mesh, index, &meta_data)?;
+// Builder comparisons only run here, not when the trees come from the cache.
+fn build_model_trees(
+    path: &str,
+    settings: &bvh::BuildSettings,
+) -> Result<bvh_cache::Groups, Box<dyn Error>> {
+    let mut trees = vec![];
+
+    for (name, mesh) in load_indexed_groups(path)? {
+        let mesh = Arc::new(mesh);
+
+        let aggregate = AggregatePrimitive {
+            primitives: (0..mesh.indices.len() as u32)
+                .map(|index| MeshTriangle {
+                    mesh: mesh.clone(),
+                    index,
+                })
+                .collect(),
+        };
+
+        if settings.compare {
+            let name = format!("{} {}", path, name);
+            bvh::compare_builders(&name, &aggregate, settings);
+        }
 
-        model.primitives.push(Box::new(BVHMesh {
-            primitive: bvh::Tree::new(mesh.primitive),
-            brdf: mesh.brdf,
-        }));
+        trees.push((name, bvh::Tree::with_settings(aggregate, settings)));
     }
 
-    Ok(model)
+    Ok(trees)
 }
 
 pub fn load_model_bvh_debug(path: &str) -> Result<Vec<BVHMesh>, Box<dyn Error>> {
     use std::path::Path;
-    let obj_mesh = obj::Obj::<obj::SimplePolygon>::load(&Path::new(path))?;
+    let obj_mesh = obj::Obj::<obj::SimplePolygon>::load(Path::new(path))?;
 
-    if obj_mesh.objects.len() < 1 {
+    if obj_mesh.objects.is_empty() {
         return Err("Failed to load obj, file needs to \
                     have at least one object"
             .into());
-    } else if obj_mesh.objects[0].groups.len() < 1 {
+    } else if obj_mesh.objects[0].groups.is_empty() {
         return Err("Failed to load obj, object needs to \
                     have at least one group"
             .into());
@@ -264,16 +582,17 @@ pub fn load_model_bvh_debug(path: &str) -> Result<Vec<BVHMesh>, Box<dyn Error>>
         meshes.push(BVHMesh {
             primitive: bvh::Tree::new(mesh.primitive),
             brdf: mesh.brdf,
+            material_id: mesh.material_id,
+            group_id: mesh.group_id,
         });
     }
 
     Ok(meshes)
 }
 
-
 pub fn load_mesh_aggregate(path: &str) -> Result<AggregatePrimitive<Triangle>, Box<dyn Error>> {
     use std::path::Path;
-    let obj_mesh = obj::Obj::<obj::SimplePolygon>::load(&Path::new(path))?;
+    let obj_mesh = obj::Obj::<obj::SimplePolygon>::load(Path::new(path))?;
 
     if obj_mesh.objects.len() != 1 {
         return Err("Failed to load obj, file needs to \
@@ -323,7 +642,7 @@ pub fn load_mesh_aggregate(path: &str) -> Result<AggregatePrimitive<Triangle>, B
             }
         }
 
-        for (pos, nrm) in v_pos.into_iter().zip(v_nrm.into_iter()) {
+        for (pos, nrm) in v_pos.into_iter().zip(v_nrm) {
             vert.push(Vertex { pos, nrm });
         }
 
@@ -0,0 +1,109 @@
+extern crate nalgebra as na;
+use na::{Isometry3, Point3, Translation3, Vector3};
+
+use crate::bvh::Bounds;
+
+#[derive(Clone, Copy)]
+pub struct Keyframe {
+    pub time: f64,
+    pub isometry: Isometry3<f64>,
+}
+
+// Piecewise interpolated isometry, translation is interpolated linearly and
+// rotation spherically. Times outside of the keyframes are clamped.
+#[derive(Clone)]
+pub struct AnimatedIsometry {
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl AnimatedIsometry {
+    pub fn new(mut keyframes: Vec<Keyframe>) -> AnimatedIsometry {
+        debug_assert!(!keyframes.is_empty());
+
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+
+        AnimatedIsometry { keyframes }
+    }
+
+    pub fn fixed(isometry: Isometry3<f64>) -> AnimatedIsometry {
+        AnimatedIsometry {
+            keyframes: vec![Keyframe {
+                time: 0.0,
+                isometry,
+            }],
+        }
+    }
+
+    pub fn is_static(&self) -> bool {
+        self.keyframes.len() == 1
+    }
+
+    pub fn at(&self, time: f64) -> Isometry3<f64> {
+        let first = &self.keyframes[0];
+        let last = &self.keyframes[self.keyframes.len() - 1];
+
+        if time <= first.time {
+            return first.isometry;
+        } else if time >= last.time {
+            return last.isometry;
+        }
+
+        let i = self.keyframes.iter().position(|k| k.time > time).unwrap();
+
+        let a = &self.keyframes[i - 1];
+        let b = &self.keyframes[i];
+
+        interpolate(
+            &a.isometry,
+            &b.isometry,
+            (time - a.time) / (b.time - a.time),
+        )
+    }
+
+    // Conservative bounds of `bounds` swept by the motion over the
+    // keyframes. Each segment is stepped and every step is padded by the
+    // largest displacement of a corner, which covers the arcs traced by
+    // rotations between steps.
+    pub fn swept_bounds(&self, bounds: &Bounds) -> Bounds {
+        const STEPS: usize = 32;
+
+        let corners = bounds.corners();
+        let mut swept = bounds.transform(&self.keyframes[0].isometry);
+
+        for w in self.keyframes.windows(2) {
+            let mut previous: Vec<Point3<f64>> =
+                corners.iter().map(|c| w[0].isometry * c).collect();
+
+            for step in 1..=STEPS {
+                let m = interpolate(&w[0].isometry, &w[1].isometry, step as f64 / STEPS as f64);
+                let current: Vec<Point3<f64>> = corners.iter().map(|c| m * c).collect();
+
+                let pad = previous
+                    .iter()
+                    .zip(current.iter())
+                    .map(|(a, b)| (b - a).norm())
+                    .fold(0.0, f64::max);
+
+                let mut step_bounds = bounds.transform(&m);
+                step_bounds.min -= Vector3::repeat(pad);
+                step_bounds.max += Vector3::repeat(pad);
+
+                swept = swept.union(&step_bounds);
+                previous = current;
+            }
+        }
+
+        swept
+    }
+}
+
+fn interpolate(a: &Isometry3<f64>, b: &Isometry3<f64>, t: f64) -> Isometry3<f64> {
+    let translation = a.translation.vector.lerp(&b.translation.vector, t);
+
+    let rotation = a
+        .rotation
+        .try_slerp(&b.rotation, t, 1.0e-9)
+        .unwrap_or(if t < 0.5 { a.rotation } else { b.rotation });
+
+    Isometry3::from_parts(Translation3::from(translation), rotation)
+}
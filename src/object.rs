@@ -1,24 +1,62 @@
 extern crate nalgebra as na;
-use na::Vector3;
+use na::{Affine3, Isometry3, Matrix3, Point3, Vector2, Vector3, U3};
+
+use std::error::Error;
+use std::sync::Arc;
 
 use crate::brdf::*;
+use crate::bvh::Bounds;
+use crate::motion::AnimatedIsometry;
 use crate::primitive;
-use crate::ray::Ray;
+use crate::ray::{transform_error, Ray};
 
+// See `primitive::IntersectionRecord` for the geometry.
 pub struct IntersectionRecord<'a> {
     pub t: f64,
     pub normal: Vector3<f64>,
-    pub brdf: &'a Box<dyn BRDF>,
+    pub point: Point3<f64>,
+    pub error: Vector3<f64>,
+    pub geometric_normal: Vector3<f64>,
+    pub uv: Vector2<f64>,
+    pub barycentric: Vector3<f64>,
+    pub brdf: &'a dyn BRDF,
+    pub material_id: u32,
+    pub group_id: u32,
 }
 
-pub trait Intersect {
-    fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord>;
-    
+pub trait Intersect: Send + Sync {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord<'_>>;
+
+    // Whether anything is hit in `[ray.t_min, t_max]`, any hit will do.
+    fn occluded(&self, ray: &Ray, t_max: f64) -> bool {
+        self.intersect(&Ray {
+            t_max: t_max.min(ray.t_max),
+            ..*ray
+        })
+        .is_some()
+    }
+
+    fn bounds(&self) -> Option<Bounds> {
+        None
+    }
+
+    // Moves vertex animated geometry to `frame` of the animation and refits
+    // the bounds around it. Everything else stays as it is.
+    fn set_frame(&mut self, _frame: usize) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    // Whether `set_frame` changes anything.
+    fn animated(&self) -> bool {
+        false
+    }
 }
 
 pub struct Object<T: primitive::Primitive> {
     pub primitive: T,
     pub brdf: Box<dyn BRDF>,
+    pub material_id: u32,
+    pub group_id: u32,
 }
 
 impl<T: primitive::Primitive> Object<T> {
@@ -28,21 +66,36 @@ impl<T: primitive::Primitive> Object<T> {
             brdf: Box::new(DiffuseBRDF {
                 color: Vector3::<f64>::repeat(1.0),
             }),
+            material_id: 0,
+            group_id: 0,
         }
     }
 }
 
 impl<T: primitive::Primitive> Intersect for Object<T> {
-    fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord> {
-        if let Some(intersect_prim) = self.primitive.intersect(&ray) {
-            Some(IntersectionRecord {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord<'_>> {
+        self.primitive
+            .intersect(ray)
+            .map(|intersect_prim| IntersectionRecord {
                 t: intersect_prim.t,
                 normal: intersect_prim.normal,
-                brdf: &self.brdf,
+                point: intersect_prim.point,
+                error: intersect_prim.error,
+                geometric_normal: intersect_prim.geometric_normal,
+                uv: intersect_prim.uv,
+                barycentric: intersect_prim.barycentric,
+                brdf: self.brdf.as_ref(),
+                material_id: self.material_id,
+                group_id: self.group_id,
             })
-        } else {
-            None
-        }
+    }
+
+    fn occluded(&self, ray: &Ray, t_max: f64) -> bool {
+        self.primitive.occluded(ray, t_max)
+    }
+
+    fn bounds(&self) -> Option<Bounds> {
+        self.primitive.bounds()
     }
 }
 
@@ -50,6 +103,12 @@ pub struct AggregateObject {
     pub primitives: Vec<Box<dyn Intersect>>,
 }
 
+impl Default for AggregateObject {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl AggregateObject {
     pub fn new() -> AggregateObject {
         AggregateObject {
@@ -59,11 +118,11 @@ impl AggregateObject {
 }
 
 impl Intersect for AggregateObject {
-    fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord> {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord<'_>> {
         let mut closest: Option<IntersectionRecord> = None;
 
         for primitive in self.primitives.iter() {
-            if let Some(record) = primitive.intersect(&ray) {
+            if let Some(record) = primitive.intersect(ray) {
                 closest = match &closest {
                     Some(old_record) if old_record.t > record.t => Some(record),
                     Some(_) => closest,
@@ -74,4 +133,224 @@ impl Intersect for AggregateObject {
 
         closest
     }
+
+    fn occluded(&self, ray: &Ray, t_max: f64) -> bool {
+        self.primitives.iter().any(|p| p.occluded(ray, t_max))
+    }
+
+    fn bounds(&self) -> Option<Bounds> {
+        let mut bounds = self.primitives.first()?.bounds()?;
+
+        for primitive in self.primitives[1..].iter() {
+            bounds = bounds.union(&primitive.bounds()?);
+        }
+
+        Some(bounds)
+    }
+
+    fn set_frame(&mut self, frame: usize) -> Result<(), Box<dyn Error>> {
+        for primitive in self.primitives.iter_mut() {
+            primitive.set_frame(frame)?;
+        }
+
+        Ok(())
+    }
+
+    fn animated(&self) -> bool {
+        self.primitives.iter().any(|p| p.animated())
+    }
+}
+
+// Object moving rigidly over the shutter interval. Rays are tested against
+// the bounds swept by the whole motion before being moved into object space
+// at their own time.
+pub struct MovingObject {
+    pub object: Box<dyn Intersect>,
+    pub motion: AnimatedIsometry,
+    swept_bounds: Option<Bounds>,
+}
+
+impl MovingObject {
+    pub fn new(object: Box<dyn Intersect>, motion: AnimatedIsometry) -> MovingObject {
+        let swept_bounds = object.bounds().map(|b| motion.swept_bounds(&b));
+
+        MovingObject {
+            object,
+            motion,
+            swept_bounds,
+        }
+    }
+}
+
+impl Intersect for MovingObject {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord<'_>> {
+        if let Some(bounds) = &self.swept_bounds {
+            if !bounds.intersect(ray) {
+                return None;
+            }
+        }
+
+        let m = self.motion.at(ray.time);
+
+        self.object
+            .intersect(&local_ray(&m, ray))
+            .map(|record| IntersectionRecord {
+                normal: m.transform_vector(&record.normal),
+                point: m * record.point,
+                error: transform_error(&m.to_homogeneous(), &record.point, &record.error),
+                geometric_normal: m.transform_vector(&record.geometric_normal),
+                ..record
+            })
+    }
+
+    fn occluded(&self, ray: &Ray, t_max: f64) -> bool {
+        if let Some(bounds) = &self.swept_bounds {
+            if !bounds.intersect(ray) {
+                return false;
+            }
+        }
+
+        let m = self.motion.at(ray.time);
+
+        self.object.occluded(&local_ray(&m, ray), t_max)
+    }
+
+    fn bounds(&self) -> Option<Bounds> {
+        self.swept_bounds.clone()
+    }
+
+    fn set_frame(&mut self, frame: usize) -> Result<(), Box<dyn Error>> {
+        if self.object.animated() {
+            self.object.set_frame(frame)?;
+            self.swept_bounds = self.object.bounds().map(|b| self.motion.swept_bounds(&b));
+        }
+
+        Ok(())
+    }
+
+    fn animated(&self) -> bool {
+        self.object.animated()
+    }
+}
+
+// Isometries keep distances, so the interval of the ray stays valid.
+fn local_ray(m: &Isometry3<f64>, ray: &Ray) -> Ray {
+    Ray {
+        origin: m.inverse_transform_point(&ray.origin),
+        direction: m.inverse_transform_vector(&ray.direction),
+        ..*ray
+    }
+}
+
+// Copy of a shared object placed with its own affine transform and
+// optionally its own material. Rays are moved into the space of the object
+// with a normalized direction, so the hit distances are scaled back.
+pub struct Instance {
+    pub object: Arc<dyn Intersect>,
+    pub brdf: Option<Box<dyn BRDF>>,
+    transform: Affine3<f64>,
+    inverse: Affine3<f64>,
+    normal_matrix: Matrix3<f64>,
+    bounds: Option<Bounds>,
+}
+
+impl Instance {
+    pub fn new(
+        object: Arc<dyn Intersect>,
+        transform: Affine3<f64>,
+        brdf: Option<Box<dyn BRDF>>,
+    ) -> Instance {
+        let inverse = transform.inverse();
+        let bounds = transformed_bounds(object.as_ref(), &transform);
+
+        Instance {
+            object,
+            brdf,
+            transform,
+            inverse,
+            normal_matrix: inverse.matrix().fixed_slice::<U3, U3>(0, 0).transpose(),
+            bounds,
+        }
+    }
+
+    // Ray in object space and the length of its unnormalized direction.
+    fn local_ray(&self, ray: &Ray) -> (Ray, f64) {
+        let direction = self.inverse * ray.direction;
+        let scale = direction.norm();
+
+        let local_ray = Ray {
+            origin: self.inverse * ray.origin,
+            direction: direction / scale,
+            time: ray.time,
+            t_min: ray.t_min * scale,
+            t_max: ray.t_max * scale,
+        };
+
+        (local_ray, scale)
+    }
+}
+
+impl Intersect for Instance {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord<'_>> {
+        if let Some(bounds) = &self.bounds {
+            if !bounds.intersect(ray) {
+                return None;
+            }
+        }
+
+        let (local_ray, scale) = self.local_ray(ray);
+
+        self.object
+            .intersect(&local_ray)
+            .map(|record| IntersectionRecord {
+                t: record.t / scale,
+                normal: (self.normal_matrix * record.normal).normalize(),
+                point: self.transform * record.point,
+                error: transform_error(self.transform.matrix(), &record.point, &record.error),
+                geometric_normal: (self.normal_matrix * record.geometric_normal).normalize(),
+                brdf: self.brdf.as_deref().unwrap_or(record.brdf),
+                ..record
+            })
+    }
+
+    fn occluded(&self, ray: &Ray, t_max: f64) -> bool {
+        if let Some(bounds) = &self.bounds {
+            if !bounds.intersect(ray) {
+                return false;
+            }
+        }
+
+        let (local_ray, scale) = self.local_ray(ray);
+
+        self.object.occluded(&local_ray, t_max * scale)
+    }
+
+    fn bounds(&self) -> Option<Bounds> {
+        self.bounds.clone()
+    }
+
+    // Animated objects are moved in place, so they can't be shared with
+    // other instances.
+    fn set_frame(&mut self, frame: usize) -> Result<(), Box<dyn Error>> {
+        if self.object.animated() {
+            Arc::get_mut(&mut self.object)
+                .ok_or("Animated objects can't be instanced")?
+                .set_frame(frame)?;
+
+            self.bounds = transformed_bounds(self.object.as_ref(), &self.transform);
+        }
+
+        Ok(())
+    }
+
+    fn animated(&self) -> bool {
+        self.object.animated()
+    }
+}
+
+fn transformed_bounds(object: &dyn Intersect, transform: &Affine3<f64>) -> Option<Bounds> {
+    object.bounds().map(|b| {
+        let corners: Vec<Point3<f64>> = b.corners().iter().map(|c| transform * c).collect();
+        Bounds::around(&corners)
+    })
 }
@@ -1,23 +1,71 @@
 extern crate nalgebra as na;
 use na::Point3;
-use na::Vector3;
+use na::{Vector2, Vector3};
 
-use crate::ray::Ray;
+use std::f64::consts::PI;
+use std::sync::Arc;
 
+use crate::bvh::Bounds;
+use crate::ray::{gamma, Ray};
+
+// `normal` is the shading normal, `geometric_normal` the one of the actual
+// surface. `point` is computed from the surface rather than from the ray and
+// is off by at most `error` per component, see `Ray::spawn`. `uv` is the
+// surface parameterization, `barycentric` the coordinates of the hit within
+// a triangle and zero on other primitives.
 #[derive(Clone)]
 pub struct IntersectionRecord {
     pub t: f64,
     pub normal: Vector3<f64>,
+    pub point: Point3<f64>,
+    pub error: Vector3<f64>,
+    pub geometric_normal: Vector3<f64>,
+    pub uv: Vector2<f64>,
+    pub barycentric: Vector3<f64>,
 }
 
-pub trait Primitive {
+pub trait Primitive: Send + Sync {
     fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord>;
+
+    // Whether anything is hit in `[ray.t_min, t_max]`, any hit will do.
+    fn occluded(&self, ray: &Ray, t_max: f64) -> bool {
+        self.intersect(&Ray {
+            t_max: t_max.min(ray.t_max),
+            ..*ray
+        })
+        .is_some()
+    }
+
+    fn bounds(&self) -> Option<Bounds> {
+        None
+    }
 }
 
+// Axis aligned bounds, for primitives that can be put into a BVH.
+pub trait Bounded {
+    fn aabb(&self) -> Bounds;
+
+    // `Primitive::intersect` given the `Shear` of the ray, which BVHs set up
+    // once for all the triangles a ray is tested against.
+    fn intersect_sheared(&self, ray: &Ray, _shear: &Shear) -> Option<IntersectionRecord>
+    where
+        Self: Primitive,
+    {
+        self.intersect(ray)
+    }
+}
+
+#[derive(Clone)]
 pub struct AggregatePrimitive<T: Primitive> {
     pub primitives: Vec<T>,
 }
 
+impl<T: Primitive> Default for AggregatePrimitive<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: Primitive> AggregatePrimitive<T> {
     pub fn new() -> AggregatePrimitive<T> {
         AggregatePrimitive::<T> {
@@ -31,7 +79,7 @@ impl<T: Primitive> Primitive for AggregatePrimitive<T> {
         let mut closest: Option<IntersectionRecord> = None;
 
         for primitive in self.primitives.iter() {
-            if let Some(record) = primitive.intersect(&ray) {
+            if let Some(record) = primitive.intersect(ray) {
                 closest = match &closest {
                     Some(old_record) if old_record.t > record.t => Some(record),
                     Some(_) => closest,
@@ -42,6 +90,10 @@ impl<T: Primitive> Primitive for AggregatePrimitive<T> {
 
         closest
     }
+
+    fn occluded(&self, ray: &Ray, t_max: f64) -> bool {
+        self.primitives.iter().any(|p| p.occluded(ray, t_max))
+    }
 }
 
 pub struct Sphere {
@@ -59,33 +111,72 @@ impl Primitive for Sphere {
     fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord> {
         let l = self.pos - ray.origin;
         let tca = l.dot(&ray.direction);
-        let d2 = l.dot(&l) - tca * tca;
 
+        // From the part of `l` perpendicular to the ray rather than
+        // l.l - tca^2, which cancels badly far from the sphere (Haines et
+        // al., "Precision Improvements for Ray/Sphere Intersection").
+        let d2 = (l - tca * ray.direction).norm_squared();
         let radius2 = self.radius * self.radius;
 
+        if d2 > radius2 {
+            return None;
+        }
+
         let thc = (radius2 - d2).sqrt();
 
-        let t0 = tca - thc;
-        let t1 = tca + thc;
+        // The root further from 0 first and the other one from the product
+        // of the roots, which doesn't cancel for origins on the surface.
+        let q = if tca >= 0.0 { tca + thc } else { tca - thc };
 
-        let mut t = t1.min(t0);
+        if q == 0.0 {
+            return None;
+        }
 
-        if t0 < std::f32::EPSILON.into() && t1 > std::f32::EPSILON.into() {
-            t = t1;
+        let c = (l.norm_squared() - radius2) / q;
+        let (t0, t1) = (q.min(c), q.max(c));
 
-            let pos = ray.origin.coords + t * ray.direction;
-            let normal = (self.pos.coords - pos).normalize();
+        // Hits within the rounding error of the roots may be the surface the
+        // ray starts on. From the inside the normal points inwards.
+        let t_min = ray.t_min.max(gamma(7) * (l.norm() + self.radius));
+        let (t, side) = if t0 >= t_min { (t0, 1.0) } else { (t1, -1.0) };
 
-            return Some(IntersectionRecord { t, normal });
+        if t < t_min || t > ray.t_max {
+            return None;
         }
 
-        if d2 > radius2 || t < std::f32::EPSILON.into() {
-            None
-        } else {
-            let pos = ray.origin.coords + t * ray.direction;
-            let normal = (pos - self.pos.coords).normalize();
+        // Moved onto the surface, then only the rounding of the last steps
+        // is left.
+        let mut local = ray.origin.coords + t * ray.direction - self.pos.coords;
+        local *= self.radius / local.norm();
+
+        let point = self.pos + local;
+        let normal = side * local.normalize();
+
+        // Longitude and latitude, with the poles on the y axis.
+        let u = local.z.atan2(local.x) / (2.0 * PI) + 0.5;
+        let v = (local.y / self.radius).clamp(-1.0, 1.0).acos() / PI;
+
+        Some(IntersectionRecord {
+            t,
+            normal,
+            point,
+            error: gamma(5) * local.abs() + gamma(1) * point.coords.abs(),
+            geometric_normal: normal,
+            uv: Vector2::new(u, v),
+            barycentric: Vector3::zeros(),
+        })
+    }
+
+    fn bounds(&self) -> Option<Bounds> {
+        Some(self.aabb())
+    }
+}
 
-            Some(IntersectionRecord { t, normal })
+impl Bounded for Sphere {
+    fn aabb(&self) -> Bounds {
+        Bounds {
+            min: self.pos.coords - Vector3::repeat(self.radius),
+            max: self.pos.coords + Vector3::repeat(self.radius),
         }
     }
 }
@@ -102,10 +193,34 @@ impl Primitive for Plane {
             let v = self.pos - ray.origin;
             let t = v.dot(&-self.nrm) / denom;
 
-            if t >= 0.0 {
+            // Hits within the rounding error of `t` may be the plane the ray
+            // starts on.
+            let delta_t = gamma(5) * v.abs().dot(&self.nrm.abs()) / denom;
+
+            if t >= ray.t_min && t <= ray.t_max && t > delta_t {
+                // Projected onto the plane, like the sphere.
+                let p = ray.origin + t * ray.direction;
+                let point =
+                    p - self.nrm * (self.nrm.dot(&(p - self.pos)) / self.nrm.norm_squared());
+
+                // Distances from `pos` along two axes in the plane.
+                let axis = if self.nrm.x.abs() > 0.9 {
+                    Vector3::y()
+                } else {
+                    Vector3::x()
+                };
+                let tangent = self.nrm.cross(&axis).normalize();
+                let bitangent = self.nrm.normalize().cross(&tangent);
+                let local = point - self.pos;
+
                 return Some(IntersectionRecord {
                     t,
                     normal: self.nrm,
+                    point,
+                    error: gamma(5) * (point.coords.abs() + self.pos.coords.abs()),
+                    geometric_normal: self.nrm,
+                    uv: Vector2::new(local.dot(&tangent), local.dot(&bitangent)),
+                    barycentric: Vector3::zeros(),
                 });
             }
         }
@@ -119,43 +234,394 @@ pub struct Vertex {
     pub nrm: Vector3<f64>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 pub struct Triangle {
-    pub vert: Vec<Vertex>,
+    pub vert: [Vertex; 3],
 }
 
 impl Triangle {
-    pub fn new(v: &Vec<Vertex>) -> Triangle {
+    pub fn new(v: &[Vertex]) -> Triangle {
         debug_assert!(v.len() == 3);
 
-        Triangle { vert: v.clone() }
+        Triangle {
+            vert: [v[0], v[1], v[2]],
+        }
+    }
+}
+
+impl Triangle {
+    fn positions(&self) -> [Vector3<f64>; 3] {
+        [self.vert[0].pos, self.vert[1].pos, self.vert[2].pos]
     }
 }
 
 impl Primitive for Triangle {
     fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord> {
-        let oa = ray.origin.coords - self.vert[0].pos;
-        let e1 = self.vert[1].pos - self.vert[0].pos;
-        let e2 = self.vert[2].pos - self.vert[0].pos;
+        self.intersect_sheared(ray, &Shear::new(&ray.direction))
+    }
+
+    fn bounds(&self) -> Option<Bounds> {
+        Some(self.aabb())
+    }
+}
+
+impl Bounded for Triangle {
+    fn aabb(&self) -> Bounds {
+        triangle_bounds(&self.positions())
+    }
+
+    fn intersect_sheared(&self, ray: &Ray, shear: &Shear) -> Option<IntersectionRecord> {
+        let p = self.positions();
+        let (t, b) = intersect_triangle(&p, ray, shear)?;
+
+        let normal = (b[0] * self.vert[0].nrm + b[1] * self.vert[1].nrm + b[2] * self.vert[2].nrm)
+            .normalize();
+
+        // No texture coordinates, see `IndexedMesh::uv`.
+        Some(triangle_record(&p, t, &b, normal, Vector2::new(b[1], b[2])))
+    }
+}
+
+// The hit point is interpolated from the vertices with the barycentric
+// coordinates `b`, with the error bound of pbrt. The geometric normal is
+// flipped to the side of the shading normal.
+fn triangle_record(
+    p: &[Vector3<f64>; 3],
+    t: f64,
+    b: &[f64; 3],
+    normal: Vector3<f64>,
+    uv: Vector2<f64>,
+) -> IntersectionRecord {
+    let (p0, p1, p2) = (b[0] * p[0], b[1] * p[1], b[2] * p[2]);
+
+    let mut geometric_normal = (p[1] - p[0]).cross(&(p[2] - p[0])).normalize();
+
+    if geometric_normal.dot(&normal) < 0.0 {
+        geometric_normal = -geometric_normal;
+    }
+
+    IntersectionRecord {
+        t,
+        normal,
+        point: (p0 + p1 + p2).into(),
+        error: gamma(7) * (p0.abs() + p1.abs() + p2.abs()),
+        geometric_normal,
+        uv,
+        barycentric: Vector3::new(b[0], b[1], b[2]),
+    }
+}
+
+fn triangle_bounds(p: &[Vector3<f64>; 3]) -> Bounds {
+    Bounds {
+        max: p[0].zip_map(&p[1].zip_map(&p[2], f64::max), f64::max) + Vector3::repeat(0.00000001),
+        min: p[0].zip_map(&p[1].zip_map(&p[2], f64::min), f64::min) - Vector3::repeat(0.00000001),
+    }
+}
+
+// Permutation of the axes making the largest component of the ray
+// direction z, and the shear turning the direction into +z. Only depends on
+// the ray, so it can be shared by the triangles it is tested against.
+pub struct Shear {
+    pub axes: [usize; 3],
+    pub s: Vector3<f64>,
+}
+
+impl Shear {
+    pub fn new(direction: &Vector3<f64>) -> Shear {
+        let kz = direction.iamax();
+        let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+
+        // Keeps the winding of the triangles.
+        if direction[kz] < 0.0 {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+
+        Shear {
+            axes: [kx, ky, kz],
+            s: Vector3::new(
+                direction[kx] / direction[kz],
+                direction[ky] / direction[kz],
+                1.0 / direction[kz],
+            ),
+        }
+    }
+}
+
+// Watertight ray triangle test (Woop, Benthin and Wald, "Watertight
+// Ray/Triangle Intersection"). The vertices are moved into a space where the
+// ray starts at the origin along +z, there the edge functions of an edge
+// come out the same for both triangles sharing it, so no ray slips through
+// between them. Returns the distance and the barycentric coordinates of the
+// vertices.
+pub fn intersect_triangle(
+    p: &[Vector3<f64>; 3],
+    ray: &Ray,
+    shear: &Shear,
+) -> Option<(f64, [f64; 3])> {
+    let [kx, ky, kz] = shear.axes;
+
+    let a = p[0] - ray.origin.coords;
+    let b = p[1] - ray.origin.coords;
+    let c = p[2] - ray.origin.coords;
+
+    let ax = a[kx] - shear.s.x * a[kz];
+    let ay = a[ky] - shear.s.y * a[kz];
+    let bx = b[kx] - shear.s.x * b[kz];
+    let by = b[ky] - shear.s.y * b[kz];
+    let cx = c[kx] - shear.s.x * c[kz];
+    let cy = c[ky] - shear.s.y * c[kz];
+
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+
+    // Hits from both sides, as long as the signs agree.
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+
+    let det = u + v + w;
+
+    if det == 0.0 {
+        return None;
+    }
+
+    let az = shear.s.z * a[kz];
+    let bz = shear.s.z * b[kz];
+    let cz = shear.s.z * c[kz];
+
+    let t = (u * az + v * bz + w * cz) / det;
+
+    if !(ray.t_min..=ray.t_max).contains(&t)
+        || t <= triangle_delta_t([ax, bx, cx], [ay, by, cy], [az, bz, cz], [u, v, w], det)
+    {
+        return None;
+    }
+
+    Some((t, [u / det, v / det, w / det]))
+}
+
+// Bound on the rounding error of `t` in `intersect_triangle`, from the
+// transformed vertices, edge functions and determinant (pbrt, section 3.9).
+// Hits closer than that may be the surface the ray starts on.
+#[inline]
+pub fn triangle_delta_t(x: [f64; 3], y: [f64; 3], z: [f64; 3], e: [f64; 3], det: f64) -> f64 {
+    let max = |v: [f64; 3]| v[0].abs().max(v[1].abs()).max(v[2].abs());
+    let (max_x, max_y, max_z, max_e) = (max(x), max(y), max(z), max(e));
+
+    let delta_x = gamma(5) * (max_x + max_z);
+    let delta_y = gamma(5) * (max_y + max_z);
+    let delta_z = gamma(3) * max_z;
+    let delta_e = 2.0 * (gamma(2) * max_x * max_y + delta_y * max_x + delta_x * max_y);
+
+    3.0 * (gamma(3) * max_e * max_z + delta_e * max_z + delta_z * max_e) / det.abs()
+}
+
+// Vertices shared by the triangles of a mesh and three vertex indices per
+// triangle. Stored as f32, the precision of OBJ files. `uvs` is empty for
+// meshes without texture coordinates.
+pub struct IndexedMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub uvs: Vec<[f32; 2]>,
+    pub indices: Vec<[u32; 3]>,
+}
+
+impl IndexedMesh {
+    pub fn positions(&self, triangle: u32) -> [Vector3<f64>; 3] {
+        let index = &self.indices[triangle as usize];
+        let position = |k: usize| {
+            let p = self.positions[index[k] as usize];
+            Vector3::new(p[0] as f64, p[1] as f64, p[2] as f64)
+        };
+
+        [position(0), position(1), position(2)]
+    }
+
+    pub fn normal(&self, triangle: u32, b: &[f64; 3]) -> Vector3<f64> {
+        let index = &self.indices[triangle as usize];
+
+        (0..3)
+            .map(|k| {
+                let n = self.normals[index[k] as usize];
+                b[k] * Vector3::new(n[0] as f64, n[1] as f64, n[2] as f64)
+            })
+            .fold(Vector3::zeros(), |sum, n| sum + n)
+            .normalize()
+    }
+
+    // Without texture coordinates the barycentric ones stand in, like in
+    // pbrt.
+    pub fn uv(&self, triangle: u32, b: &[f64; 3]) -> Vector2<f64> {
+        if self.uvs.is_empty() {
+            return Vector2::new(b[1], b[2]);
+        }
+
+        let index = &self.indices[triangle as usize];
+
+        (0..3)
+            .map(|k| {
+                let uv = self.uvs[index[k] as usize];
+                b[k] * Vector2::new(uv[0] as f64, uv[1] as f64)
+            })
+            .fold(Vector2::zeros(), |sum, uv| sum + uv)
+    }
+}
+
+// Triangle `index` of `mesh`, small enough to keep millions in a BVH.
+#[derive(Clone)]
+pub struct MeshTriangle {
+    pub mesh: Arc<IndexedMesh>,
+    pub index: u32,
+}
+
+impl MeshTriangle {
+    pub fn positions(&self) -> [Vector3<f64>; 3] {
+        self.mesh.positions(self.index)
+    }
+
+    // Record of a hit at distance `t` with barycentric coordinates `b`, as
+    // found by `intersect_triangle`.
+    pub fn record(&self, t: f64, b: &[f64; 3]) -> IntersectionRecord {
+        triangle_record(
+            &self.positions(),
+            t,
+            b,
+            self.mesh.normal(self.index, b),
+            self.mesh.uv(self.index, b),
+        )
+    }
+}
+
+impl Primitive for MeshTriangle {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord> {
+        self.intersect_sheared(ray, &Shear::new(&ray.direction))
+    }
+
+    fn bounds(&self) -> Option<Bounds> {
+        Some(self.aabb())
+    }
+}
+
+impl Bounded for MeshTriangle {
+    fn aabb(&self) -> Bounds {
+        triangle_bounds(&self.positions())
+    }
+
+    fn intersect_sheared(&self, ray: &Ray, shear: &Shear) -> Option<IntersectionRecord> {
+        let (t, b) = intersect_triangle(&self.positions(), ray, shear)?;
+
+        Some(self.record(t, &b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(p: &[Vector3<f64>; 3], ray: &Ray) -> Option<(f64, [f64; 3])> {
+        intersect_triangle(p, ray, &Shear::new(&ray.direction))
+    }
 
-        let p = ray.direction.cross(&e2);
-        let q = oa.cross(&e1);
+    fn ray_towards(origin: Vector3<f64>, target: Vector3<f64>) -> Ray {
+        Ray::new(origin.into(), (target - origin).normalize(), 0.0)
+    }
+
+    #[test]
+    fn edges_and_vertices_are_hit() {
+        let p = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+        ];
 
-        let inv = 1.0 / p.dot(&e1);
+        let on_edge = ray_towards(Vector3::new(0.5, 0.0, 1.0), Vector3::new(0.5, 0.0, 0.0));
+        let (t, b) = hit(&p, &on_edge).unwrap();
 
-        let t = inv * q.dot(&e2);
-        let u = inv * p.dot(&oa);
-        let v = inv * q.dot(&ray.direction);
+        assert_eq!(t, 1.0);
+        assert_eq!(b[2], 0.0);
 
-        if u < 0.0 || u > 1.0 || v < 0.0 || u + v > 1.0 || t < 0.0 {
-            None
-        } else {
-            let w = 1.0 - u - v;
+        let on_vertex = ray_towards(Vector3::new(1.0, 0.0, -2.0), Vector3::new(1.0, 0.0, 0.0));
+        assert_eq!(hit(&p, &on_vertex).unwrap().1, [0.0, 1.0, 0.0]);
 
-            let normal =
-                (w * self.vert[0].nrm + u * self.vert[1].nrm + v * self.vert[2].nrm).normalize();
+        let outside = ray_towards(
+            Vector3::new(0.5, -1e-12, 1.0),
+            Vector3::new(0.5, -1e-12, 0.0),
+        );
+        assert!(hit(&p, &outside).is_none());
+    }
 
-            Some(IntersectionRecord { t, normal })
+    // Rays towards points along the edge two triangles share have to hit at
+    // least one of them, whatever the rounding of the edge functions.
+    #[test]
+    fn shared_edges_have_no_cracks() {
+        let a = Vector3::new(0.1, 0.7, 0.3);
+        let b = Vector3::new(1.3, 0.2, -0.4);
+        let left = [a, b, Vector3::new(0.2, -0.9, 0.6)];
+        let right = [b, a, Vector3::new(1.1, 1.4, -0.1)];
+
+        let origins = [
+            Vector3::new(0.3, 0.1, 5.0),
+            Vector3::new(-3.7, 2.9, -1.3),
+            Vector3::new(7.1, -0.3, 0.9),
+        ];
+
+        for origin in origins.iter() {
+            for i in 1..10000 {
+                let target = a.lerp(&b, i as f64 / 10000.0);
+                let ray = ray_towards(*origin, target);
+
+                let hits = [hit(&left, &ray), hit(&right, &ray)];
+                assert!(
+                    hits.iter().any(|h| h.is_some()),
+                    "Ray {} from {} slips through",
+                    i,
+                    origin
+                );
+            }
         }
     }
+
+    #[test]
+    fn shared_vertices_have_no_holes() {
+        let center = Vector3::new(0.3, -0.2, 0.1);
+        let ring: Vec<Vector3<f64>> = (0..7)
+            .map(|k| {
+                let phi = 2.0 * std::f64::consts::PI * k as f64 / 7.0;
+                center + Vector3::new(phi.cos(), phi.sin(), 0.3 * (3.0 * phi).sin())
+            })
+            .collect();
+
+        let fan: Vec<[Vector3<f64>; 3]> = (0..7)
+            .map(|k| [center, ring[k], ring[(k + 1) % 7]])
+            .collect();
+
+        for i in 0..1000 {
+            let phi = 0.37 * i as f64;
+            let origin = center + 4.0 * Vector3::new(phi.cos(), phi.sin(), 1.5);
+            let ray = ray_towards(origin, center);
+
+            assert!(
+                fan.iter().any(|p| hit(p, &ray).is_some()),
+                "Ray {} slips through",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn both_sides_are_hit() {
+        let p = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+        ];
+        let target = Vector3::new(0.25, 0.25, 0.0);
+
+        let front = hit(&p, &ray_towards(Vector3::new(0.2, 0.3, 2.0), target)).unwrap();
+        let back = hit(&p, &ray_towards(Vector3::new(0.2, 0.3, -2.0), target)).unwrap();
+
+        assert_eq!(front.1, back.1);
+        assert!((front.0 - back.0).abs() < 1e-12);
+    }
 }
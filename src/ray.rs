@@ -1,9 +1,71 @@
 extern crate nalgebra as na;
-use na::Point3;
-use na::Vector3;
+use na::{Matrix4, Point3, Vector3, U1, U3};
 
+// Only hits with `t` in `[t_min, t_max]` count.
 #[derive(Clone, Copy)]
 pub struct Ray {
     pub origin: Point3<f64>,
     pub direction: Vector3<f64>,
+    pub time: f64,
+    pub t_min: f64,
+    pub t_max: f64,
+}
+
+impl Ray {
+    pub fn new(origin: Point3<f64>, direction: Vector3<f64>, time: f64) -> Ray {
+        Ray {
+            origin,
+            direction,
+            time,
+            t_min: 0.0,
+            t_max: f64::INFINITY,
+        }
+    }
+
+    // Ray leaving a surface at `point`, which is off by at most `error`
+    // per component. The origin is pushed along the geometric normal `ng`
+    // just past the error bound, to the side `direction` leaves through, so
+    // the ray can't hit the surface it starts on whatever the scene scale.
+    pub fn spawn(
+        point: &Point3<f64>,
+        error: &Vector3<f64>,
+        ng: &Vector3<f64>,
+        direction: &Vector3<f64>,
+        time: f64,
+    ) -> Ray {
+        let mut offset = ng.abs().dot(error) * ng;
+
+        if direction.dot(ng) < 0.0 {
+            offset = -offset;
+        }
+
+        // Rounding could move the origin back towards the surface.
+        let origin = (point.coords + offset).zip_map(&offset, |p, o| {
+            if o > 0.0 {
+                p.next_up()
+            } else if o < 0.0 {
+                p.next_down()
+            } else {
+                p
+            }
+        });
+
+        Ray::new(origin.into(), *direction, time)
+    }
+}
+
+// Bound on the relative error of `n` floating point operations in a row
+// (Higham, "Accuracy and Stability of Numerical Algorithms").
+pub const fn gamma(n: u32) -> f64 {
+    let e = n as f64 * f64::EPSILON * 0.5;
+    e / (1.0 - e)
+}
+
+// Error bound of `p`, off by at most `error`, after transforming it by the
+// affine matrix `m`.
+pub fn transform_error(m: &Matrix4<f64>, p: &Point3<f64>, error: &Vector3<f64>) -> Vector3<f64> {
+    let linear = m.fixed_slice::<U3, U3>(0, 0).abs();
+    let translation = m.fixed_slice::<U3, U1>(0, 3).abs();
+
+    (gamma(3) + 1.0) * (linear * error) + gamma(3) * (linear * p.coords.abs() + translation)
 }
@@ -0,0 +1,326 @@
+extern crate nalgebra as na;
+use na::{Vector2, Vector3};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::bdpt;
+use crate::checkpoint;
+use crate::debug;
+use crate::film::{Aovs, Film, Pixel, TileBounds};
+use crate::integrator::{ambient_occlusion, radiance};
+use crate::scene::{Integrator, RenderSettings, Scene};
+use crate::stats;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub fn install_interrupt_handler() {
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst)).unwrap();
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub fn write_checkpoint(scene: &Scene, film: &Film) {
+    let path = &scene.settings.checkpoint.as_ref().unwrap().path;
+
+    if let Err(e) = checkpoint::save(path, scene, film) {
+        println!("Failed to write checkpoint {}: {}", path, e);
+    }
+}
+
+pub fn write_snapshot(film: &Film, path: &str) {
+    if let Err(e) = film.to_image().save(path) {
+        println!("Failed to write snapshot {}: {}", path, e);
+    }
+}
+
+// Pixels that still need samples in the next pass.
+fn active_pixels(film: &Film, tiles: &[TileBounds], settings: &RenderSettings) -> Vec<bool> {
+    let adaptive = match &settings.adaptive {
+        Some(adaptive) => adaptive,
+        None => {
+            return film
+                .pixels
+                .iter()
+                .map(|p| p.samples < settings.spp)
+                .collect()
+        }
+    };
+
+    let noisy = |p: &Pixel| p.samples < settings.spp && p.relative_error() > adaptive.threshold;
+
+    let mut active: Vec<bool> = film
+        .pixels
+        .iter()
+        .map(|p| p.samples < adaptive.min_spp.min(settings.spp) || noisy(p))
+        .collect();
+
+    if adaptive.per_tile {
+        for bounds in tiles {
+            let pixels = || {
+                (bounds.y0..bounds.y1).flat_map(move |j| {
+                    (bounds.x0..bounds.x1).map(move |i| (j * film.width + i) as usize)
+                })
+            };
+
+            if pixels().any(|k| noisy(&film.pixels[k])) {
+                for k in pixels() {
+                    active[k] = film.pixels[k].samples < settings.spp;
+                }
+            }
+        }
+    }
+
+    active
+}
+
+// Renders the image in passes of `pass_spp` samples per pixel, so the film
+// always holds a usable estimate. Every pixel continues from its own sample
+// count, which lets a resumed film or an interrupted pass catch up. Stops
+// early on Ctrl-C or when the time budget runs out.
+pub fn render(scene: &Scene, film: Film) -> Film {
+    let settings = &scene.settings;
+
+    let tiles = film.tiles(16);
+    let film = Mutex::new(film);
+
+    let (pass_spp, snapshot_interval, time_budget) = match &settings.progressive {
+        Some(progressive) => (
+            progressive.pass_spp.max(1),
+            progressive.snapshot_interval.map(Duration::from_secs_f64),
+            progressive.time_budget.map(Duration::from_secs_f64),
+        ),
+        None => match &settings.adaptive {
+            Some(adaptive) => (adaptive.pass_spp.max(1), None, None),
+            None => (settings.spp, None, None),
+        },
+    };
+
+    let checkpoint_interval = settings
+        .checkpoint
+        .as_ref()
+        .and_then(|c| c.interval)
+        .map(Duration::from_secs_f64);
+
+    let pb = ProgressBar::new(settings.width as u64 * settings.height as u64 * settings.spp as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed}] [{bar:40.cyan/blue}] {msg:.blue} ({eta:.red})")
+            .progress_chars("=> "),
+    );
+
+    // Traversal cost and samples per pixel, for the heatmap.
+    let costs = Mutex::new(vec![(0, 0); (settings.width * settings.height) as usize]);
+    let profile = settings.profile.is_some();
+
+    let start = Instant::now();
+    let last_snapshot = Mutex::new(Instant::now());
+    let last_checkpoint = Mutex::new(Instant::now());
+
+    let should_stop =
+        || interrupted() || time_budget.is_some_and(|budget| start.elapsed() >= budget);
+
+    loop {
+        let (counts, active) = {
+            let film = film.lock().unwrap();
+            let counts: Vec<u32> = film.pixels.iter().map(|p| p.samples).collect();
+
+            (counts, active_pixels(&film, &tiles, settings))
+        };
+
+        let active_count = active.iter().filter(|&&a| a).count();
+
+        pb.set_position(counts.iter().map(|&c| c.min(settings.spp) as u64).sum());
+
+        if active_count == 0 || should_stop() {
+            break;
+        }
+
+        let min_count = *counts.iter().min().unwrap();
+
+        pb.set_message(&match settings.adaptive {
+            Some(_) => format!(
+                "{}x{} {} active pixels, {}/{}spp",
+                settings.width, settings.height, active_count, min_count, settings.spp
+            ),
+            None => format!(
+                "{}x{} {}/{}spp",
+                settings.width,
+                settings.height,
+                (min_count + pass_spp).min(settings.spp),
+                settings.spp
+            ),
+        });
+
+        tiles.par_iter().for_each(|bounds| {
+            let pixel_index = |i: u32, j: u32| (j * settings.width + i) as usize;
+
+            let tile_active = (bounds.y0..bounds.y1)
+                .any(|j| (bounds.x0..bounds.x1).any(|i| active[pixel_index(i, j)]));
+
+            if !tile_active || should_stop() {
+                return;
+            }
+
+            let mut tile = film.lock().unwrap().tile(bounds);
+            let mut sampler = scene.sampler.clone_box();
+            let mut tile_costs = vec![];
+
+            for j in bounds.y0..bounds.y1 {
+                for i in bounds.x0..bounds.x1 {
+                    if !active[pixel_index(i, j)] {
+                        continue;
+                    }
+
+                    let pixel = Vector2::new(i, j);
+
+                    let first = counts[pixel_index(i, j)];
+                    let last = (first + pass_spp).min(settings.spp);
+
+                    let cost = stats::local().cost();
+
+                    for index in first..last {
+                        sampler.start_pixel_sample(&pixel, index);
+
+                        let p = pixel.map(|x| x as f64) + sampler.get_2d();
+                        let time = settings.shutter_time(sampler.get_1d());
+
+                        let mut aovs = Aovs::new();
+
+                        let c = match scene.camera.get_ray(&p, time) {
+                            Some(ray) => {
+                                stats::count(|c| c.primary_rays += 1);
+
+                                match (&settings.debug, &settings.ao) {
+                                    (Some(debug), _) => debug::radiance(
+                                        debug,
+                                        ray,
+                                        scene,
+                                        sampler.as_mut(),
+                                        &mut aovs,
+                                    ),
+                                    (None, Some(ao)) => ambient_occlusion(
+                                        ao,
+                                        ray,
+                                        scene,
+                                        sampler.as_mut(),
+                                        &mut aovs,
+                                    ),
+                                    (None, None) => match settings.integrator {
+                                        Integrator::Path => radiance(
+                                            settings.depth,
+                                            ray,
+                                            scene,
+                                            sampler.as_mut(),
+                                            &mut aovs,
+                                        ),
+                                        Integrator::Bidirectional => bdpt::radiance(
+                                            settings.depth,
+                                            ray,
+                                            scene,
+                                            sampler.as_mut(),
+                                            &mut aovs,
+                                            &mut tile,
+                                        ),
+                                    },
+                                }
+                            }
+                            None => Vector3::zeros(),
+                        };
+
+                        tile.add_sample(&p, &c, &aovs);
+                    }
+
+                    if profile {
+                        let cost = stats::local().cost() - cost;
+                        tile_costs.push((pixel_index(i, j), cost, last - first));
+                    }
+
+                    pb.inc((last - first) as u64);
+                }
+            }
+
+            stats::flush();
+
+            if profile {
+                let mut costs = costs.lock().unwrap();
+
+                for (k, cost, samples) in tile_costs {
+                    costs[k].0 += cost;
+                    costs[k].1 += samples;
+                }
+            }
+
+            let mut film = film.lock().unwrap();
+            film.merge_tile(tile);
+
+            if let Some(interval) = snapshot_interval {
+                let mut last = last_snapshot.lock().unwrap();
+
+                if last.elapsed() >= interval {
+                    write_snapshot(&film, &settings.output);
+                    *last = Instant::now();
+                }
+            }
+
+            if let Some(interval) = checkpoint_interval {
+                let mut last = last_checkpoint.lock().unwrap();
+
+                if last.elapsed() >= interval {
+                    write_checkpoint(scene, &film);
+                    *last = Instant::now();
+                }
+            }
+        });
+
+        if settings.progressive.is_some() && snapshot_interval.is_none() {
+            write_snapshot(&film.lock().unwrap(), &settings.output);
+        }
+    }
+
+    pb.finish();
+
+    let film = film.into_inner().unwrap();
+
+    if settings.checkpoint.is_some() {
+        write_checkpoint(scene, &film);
+    }
+
+    if let Some(path) = settings
+        .adaptive
+        .as_ref()
+        .and_then(|a| a.sample_map.as_ref())
+    {
+        if let Err(e) = film.sample_map(settings.spp).save(path) {
+            println!("Failed to write sample map {}: {}", path, e);
+        }
+    }
+
+    if let Some(path) = settings.profile.as_ref().and_then(|p| p.heatmap.as_ref()) {
+        let costs: Vec<f64> = costs
+            .into_inner()
+            .unwrap()
+            .iter()
+            .map(|(cost, samples)| *cost as f64 / (*samples).max(1) as f64)
+            .collect();
+
+        if let Err(e) = stats::heatmap(film.width, film.height, &costs).save(path) {
+            println!("Failed to write heatmap {}: {}", path, e);
+        }
+    }
+
+    if interrupted() {
+        let min_count = film.pixels.iter().map(|p| p.samples).min().unwrap();
+
+        println!(" ");
+        println!("Interrupted, stopping at {} spp", min_count);
+    }
+
+    film
+}
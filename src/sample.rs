@@ -1,5 +1,5 @@
 extern crate nalgebra as na;
-use na::{Isometry3, Point3, Vector3};
+use na::{Isometry3, Point3, Vector2, Vector3};
 
 use crate::object;
 use crate::ray::Ray;
@@ -17,24 +17,26 @@ pub fn onb(origin: &Point3<f64>, z: &Vector3<f64>) -> Isometry3<f64> {
     let is_collinear = dot.abs() < 1.000001 && dot.abs() > 0.999999;
 
     if is_collinear {
-        Isometry3::face_towards(&origin, &(origin + z), &WORLD_RIGHT).inverse()
+        Isometry3::face_towards(origin, &(origin + z), &WORLD_RIGHT).inverse()
     } else {
-        Isometry3::face_towards(&origin, &(origin + z), &WORLD_UP).inverse()
+        Isometry3::face_towards(origin, &(origin + z), &WORLD_UP).inverse()
     }
 }
 
 pub struct SampleRecord {
     pub o: Point3<f64>,
-    pub on: Vector3<f64>,
+    pub error: Vector3<f64>,
+    pub ng: Vector3<f64>,
     pub m: Isometry3<f64>,
     pub n: Vector3<f64>,
     pub v: Vector3<f64>,
     pub p: Point3<f64>,
+    pub time: f64,
 }
 
 impl SampleRecord {
     pub fn new(ray: &Ray, record: &object::IntersectionRecord) -> SampleRecord {
-        let o: Point3<f64> = (ray.origin.coords + record.t * ray.direction).into();
+        let o = record.point;
         let m = onb(&o, &record.normal);
 
         let p = m * o;
@@ -43,24 +45,43 @@ impl SampleRecord {
 
         SampleRecord {
             o,
-            on: record.normal,
+            error: record.error,
+            ng: record.geometric_normal,
             m,
             n,
             v,
             p,
+            time: ray.time,
         }
     }
 }
 
+impl SampleRecord {
+    // Ray leaving the surface in the world space `direction`.
+    pub fn spawn(&self, direction: &Vector3<f64>) -> Ray {
+        Ray::spawn(&self.o, &self.error, &self.ng, direction, self.time)
+    }
+}
+
 pub fn reflect_onb(v: &Vector3<f64>) -> Vector3<f64> {
     Vector3::new(-v.x, -v.y, v.z)
 }
 
-pub fn uniform_hemisphere() -> Vector3<f64> {
-    use rand::random;
-
-    let theta = random::<f64>() * PI * 2.0;
-    let phi = (1.0 - 1.0 * random::<f64>()).acos();
+pub fn uniform_hemisphere(u: &Vector2<f64>) -> Vector3<f64> {
+    let theta = u.x * PI * 2.0;
+    let phi = (1.0 - 1.0 * u.y).acos();
 
     Vector3::<f64>::new(phi.sin() * theta.cos(), phi.sin() * theta.sin(), phi.cos()).normalize()
 }
+
+// Directions around +z with a pdf of cos(theta) / pi (Malley's method).
+pub fn cosine_hemisphere(u: &Vector2<f64>) -> Vector3<f64> {
+    let r = u.x.sqrt();
+    let theta = u.y * PI * 2.0;
+
+    Vector3::new(
+        r * theta.cos(),
+        r * theta.sin(),
+        (1.0 - u.x).max(0.0).sqrt(),
+    )
+}
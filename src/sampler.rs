@@ -0,0 +1,397 @@
+extern crate nalgebra as na;
+use na::Vector2;
+
+// Samplers hand out the dimensions of one pixel sample at a time. Every
+// value is a pure function of the seed, pixel, sample index and dimension,
+// so samplers can be cloned per thread and resumed without extra state.
+pub trait Sampler: Send + Sync {
+    fn start_pixel_sample(&mut self, pixel: &Vector2<u32>, index: u32);
+
+    fn get_1d(&mut self) -> f64;
+
+    fn get_2d(&mut self) -> Vector2<f64>;
+
+    fn clone_box(&self) -> Box<dyn Sampler>;
+
+    // Identify the sample sequence, a checkpoint only resumes with the same
+    // sampler type and seed.
+    fn name(&self) -> &'static str;
+
+    fn seed(&self) -> u32;
+}
+
+// lowbias32 from https://nullprogram.com/blog/2018/07/31/
+pub fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
+}
+
+pub fn hash_combine(seed: u32, v: u32) -> u32 {
+    hash(
+        seed ^ v
+            .wrapping_add(0x9e37_79b9)
+            .wrapping_add(seed << 6)
+            .wrapping_add(seed >> 2),
+    )
+}
+
+fn to_unit(x: u32) -> f64 {
+    x as f64 / 4_294_967_296.0
+}
+
+#[derive(Clone, Copy, Default)]
+struct PixelSample {
+    seed: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl PixelSample {
+    fn start(&mut self, seed: u32, pixel: &Vector2<u32>, index: u32) {
+        self.seed = hash_combine(hash_combine(seed, pixel.x), pixel.y);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_dimension(&mut self, n: u32) -> u32 {
+        let dimension = self.dimension;
+        self.dimension += n;
+        dimension
+    }
+
+    fn uniform(&self, dimension: u32, salt: u32) -> f64 {
+        to_unit(hash_combine(
+            hash_combine(hash_combine(self.seed, self.index), dimension),
+            salt,
+        ))
+    }
+}
+
+#[derive(Clone)]
+pub struct IndependentSampler {
+    pub seed: u32,
+    sample: PixelSample,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u32) -> IndependentSampler {
+        IndependentSampler {
+            seed,
+            sample: PixelSample::default(),
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, pixel: &Vector2<u32>, index: u32) {
+        self.sample.start(self.seed, pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let d = self.sample.next_dimension(1);
+        self.sample.uniform(d, 0)
+    }
+
+    fn get_2d(&mut self) -> Vector2<f64> {
+        let d = self.sample.next_dimension(2);
+        Vector2::new(self.sample.uniform(d, 0), self.sample.uniform(d + 1, 0))
+    }
+
+    fn clone_box(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+
+    fn name(&self) -> &'static str {
+        "independent"
+    }
+
+    fn seed(&self) -> u32 {
+        self.seed
+    }
+}
+
+// Permutation of [0, l) indexed by `p`, from Kensler's "Correlated
+// Multi-Jittered Sampling".
+pub fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+
+        if i < l {
+            break;
+        }
+    }
+
+    i.wrapping_add(p) % l
+}
+
+// Jittered strata per dimension, the strata of different dimensions are
+// shuffled against each other. Indices past `spp` start a new round of
+// strata with a different shuffle.
+#[derive(Clone)]
+pub struct StratifiedSampler {
+    pub seed: u32,
+    pub spp: u32,
+    sample: PixelSample,
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u32, spp: u32) -> StratifiedSampler {
+        StratifiedSampler {
+            seed,
+            spp: spp.max(1),
+            sample: PixelSample::default(),
+        }
+    }
+
+    fn stratum(&self, dimension: u32, n: u32) -> u32 {
+        let round = self.sample.index / n;
+        let p = hash_combine(hash_combine(self.sample.seed, dimension), round);
+
+        permute(self.sample.index % n, n, p)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, pixel: &Vector2<u32>, index: u32) {
+        self.sample.start(self.seed, pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let d = self.sample.next_dimension(1);
+        let stratum = self.stratum(d, self.spp);
+
+        (stratum as f64 + self.sample.uniform(d, 0)) / self.spp as f64
+    }
+
+    fn get_2d(&mut self) -> Vector2<f64> {
+        let d = self.sample.next_dimension(2);
+
+        let nx = (self.spp as f64).sqrt().floor().max(1.0) as u32;
+        let ny = self.spp.div_ceil(nx);
+
+        let stratum = self.stratum(d, nx * ny);
+
+        Vector2::new(
+            ((stratum % nx) as f64 + self.sample.uniform(d, 0)) / nx as f64,
+            ((stratum / nx) as f64 + self.sample.uniform(d + 1, 0)) / ny as f64,
+        )
+    }
+
+    fn clone_box(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+
+    fn name(&self) -> &'static str {
+        "stratified"
+    }
+
+    fn seed(&self) -> u32 {
+        self.seed
+    }
+}
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+// Radical inverse with every digit down to 1e-10, including the trailing
+// zeros, permuted depending on the digits before it.
+fn owen_scrambled_radical_inverse(base: u32, mut i: u32, seed: u32) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_m = 1.0;
+    let mut reversed_digits: u64 = 0;
+
+    while inv_base_m * (base - 1) as f64 > 1.0e-10 {
+        let digit = i % base;
+        let digit_seed = hash_combine(seed, (reversed_digits ^ (reversed_digits >> 32)) as u32);
+
+        reversed_digits = reversed_digits * base as u64 + permute(digit, base, digit_seed) as u64;
+        inv_base_m *= inv_base;
+        i /= base;
+    }
+
+    (reversed_digits as f64 * inv_base_m).min(1.0 - f64::EPSILON)
+}
+
+#[derive(Clone)]
+pub struct HaltonSampler {
+    pub seed: u32,
+    sample: PixelSample,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u32) -> HaltonSampler {
+        HaltonSampler {
+            seed,
+            sample: PixelSample::default(),
+        }
+    }
+
+    fn sample_dimension(&self, dimension: u32) -> f64 {
+        if dimension as usize >= PRIMES.len() {
+            return self.sample.uniform(dimension, 0);
+        }
+
+        owen_scrambled_radical_inverse(
+            PRIMES[dimension as usize],
+            self.sample.index,
+            hash_combine(self.sample.seed, dimension),
+        )
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, pixel: &Vector2<u32>, index: u32) {
+        self.sample.start(self.seed, pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let d = self.sample.next_dimension(1);
+        self.sample_dimension(d)
+    }
+
+    fn get_2d(&mut self) -> Vector2<f64> {
+        let d = self.sample.next_dimension(2);
+        Vector2::new(self.sample_dimension(d), self.sample_dimension(d + 1))
+    }
+
+    fn clone_box(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+
+    fn name(&self) -> &'static str {
+        "halton"
+    }
+
+    fn seed(&self) -> u32 {
+        self.seed
+    }
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x ^= x.wrapping_mul(0x3d20_adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x0552_6c56);
+    x ^= x.wrapping_mul(0x53a2_2864);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn sobol(index: u32, dimension: u32) -> u32 {
+    if dimension == 0 {
+        return index.reverse_bits();
+    }
+
+    let mut v = 1 << 31;
+    let mut result = 0;
+    let mut i = index;
+
+    while i > 0 {
+        if i & 1 == 1 {
+            result ^= v;
+        }
+
+        i >>= 1;
+        v ^= v >> 1;
+    }
+
+    result
+}
+
+// Owen scrambled Sobol, padded from the first two dimensions by shuffling
+// the sample index per dimension pair as in Burley's "Practical Hash-based
+// Owen Scrambling".
+#[derive(Clone)]
+pub struct SobolSampler {
+    pub seed: u32,
+    sample: PixelSample,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u32) -> SobolSampler {
+        SobolSampler {
+            seed,
+            sample: PixelSample::default(),
+        }
+    }
+
+    fn sample_dimension(&self, dimension: u32, n: u32) -> Vector2<f64> {
+        let pair_seed = hash_combine(self.sample.seed, dimension);
+        let index = nested_uniform_scramble(self.sample.index, pair_seed);
+
+        let mut result = Vector2::zeros();
+
+        for k in 0..n {
+            let x = nested_uniform_scramble(
+                sobol(index, k),
+                hash_combine(pair_seed, k.wrapping_add(1)),
+            );
+            result[k as usize] = to_unit(x);
+        }
+
+        result
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, pixel: &Vector2<u32>, index: u32) {
+        self.sample.start(self.seed, pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let d = self.sample.next_dimension(1);
+        self.sample_dimension(d, 1).x
+    }
+
+    fn get_2d(&mut self) -> Vector2<f64> {
+        let d = self.sample.next_dimension(2);
+        self.sample_dimension(d, 2)
+    }
+
+    fn clone_box(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+
+    fn name(&self) -> &'static str {
+        "sobol"
+    }
+
+    fn seed(&self) -> u32 {
+        self.seed
+    }
+}
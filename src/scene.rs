@@ -0,0 +1,774 @@
+extern crate nalgebra as na;
+extern crate serde_json;
+
+use na::{
+    Affine3, Isometry3, Matrix4, Point3, Translation3, Unit, UnitQuaternion, Vector2, Vector3,
+};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, OnceLock};
+
+use crate::aov::{self, Aov};
+use crate::bvh::{BuildSettings, Builder, ObjectTree};
+use crate::camera::*;
+use crate::debug::DebugMode;
+use crate::film::*;
+use crate::light::*;
+use crate::mesh;
+use crate::motion::{AnimatedIsometry, Keyframe};
+use crate::object::{Instance, Intersect, MovingObject};
+use crate::sampler::*;
+
+pub struct Scene {
+    pub obj: Box<dyn Intersect>,
+    pub lights: Vec<Box<dyn Light>>,
+    pub camera: Box<dyn Camera>,
+    pub filter: Arc<dyn Filter>,
+    pub sampler: Box<dyn Sampler>,
+    pub settings: RenderSettings,
+    description: serde_json::Value,
+    hash: OnceLock<u64>,
+}
+
+impl Scene {
+    pub fn get_light(&self, u: f64) -> &dyn Light {
+        let i = (u * self.lights.len() as f64).floor() as usize;
+        self.lights[i.min(self.lights.len() - 1)].as_ref()
+    }
+
+    // Diagonal of the bounds, a stand-in for unbounded scenes.
+    pub fn size(&self) -> f64 {
+        self.obj.bounds().map_or(10.0, |b| (b.max - b.min).norm())
+    }
+
+    // See `scene_hash`. Only checkpoints need it, and it reads every model,
+    // so it's computed the first time it's asked for.
+    pub fn hash(&self) -> Result<u64, Box<dyn Error>> {
+        if let Some(hash) = self.hash.get() {
+            return Ok(*hash);
+        }
+
+        let hash = scene_hash(&self.description)?;
+        Ok(*self.hash.get_or_init(|| hash))
+    }
+
+    // Moves the vertex animated models to `frame`.
+    pub fn set_frame(&mut self, frame: u32) -> Result<(), Box<dyn Error>> {
+        self.obj.set_frame(frame as usize)
+    }
+}
+
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub spp: u32,
+    pub depth: i32,
+    pub output: String,
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+    // Frames of the vertex animation, one more than the longest list of
+    // "frames" of the objects.
+    pub frames: u32,
+    pub progressive: Option<ProgressiveSettings>,
+    pub checkpoint: Option<CheckpointSettings>,
+    pub adaptive: Option<AdaptiveSettings>,
+    pub denoise: Option<DenoiseSettings>,
+    pub aovs: Option<AovSettings>,
+    pub profile: Option<ProfileSettings>,
+    pub debug: Option<DebugSettings>,
+    pub ao: Option<AoSettings>,
+    pub integrator: Integrator,
+    // Firefly suppression, off unless set since both bias the image. The
+    // clamps limit the largest component of a single path contribution,
+    // `regularize` is the roughness given to near specular BRDFs after the
+    // first rough bounce.
+    pub clamp_direct: Option<f64>,
+    pub clamp_indirect: Option<f64>,
+    pub regularize: Option<f64>,
+}
+
+pub struct ProgressiveSettings {
+    pub pass_spp: u32,
+    pub snapshot_interval: Option<f64>,
+    pub time_budget: Option<f64>,
+}
+
+// Pixels take at least `min_spp` samples and keep sampling up to `spp` (or
+// "max_spp" in the scene file) while their relative error is above
+// `threshold`. With `per_tile` a tile keeps sampling all of its pixels while
+// any of them is above the threshold.
+pub struct AdaptiveSettings {
+    pub threshold: f64,
+    pub min_spp: u32,
+    pub pass_spp: u32,
+    pub per_tile: bool,
+    pub sample_map: Option<String>,
+}
+
+// `sigma_color` scales the standard deviation of a pixel, `sigma_normal` is
+// the exponent of the normal cosine, `sigma_depth` the relative depth change
+// allowed per pixel and `sigma_albedo` the albedo distance.
+pub struct DenoiseSettings {
+    pub iterations: u32,
+    pub sigma_color: f64,
+    pub sigma_normal: f64,
+    pub sigma_depth: f64,
+    pub sigma_albedo: f64,
+    pub output: String,
+}
+
+pub struct AovSettings {
+    pub aovs: Vec<Aov>,
+    pub output: String,
+}
+
+// Light transport of normal renders, "path" or "bdpt" in the scene file.
+// The firefly clamps and regularization only apply to the path tracer.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Integrator {
+    Path,
+    Bidirectional,
+}
+
+// Ray statistics are printed after rendering, `heatmap` is an image of the
+// traversal cost per pixel.
+pub struct ProfileSettings {
+    pub heatmap: Option<String>,
+}
+
+// Render mode showing one property of the first hit instead of the light,
+// see `debug::DebugMode`. `distance` is the depth shown as black and the
+// reach of ambient occlusion rays, the size of the scene if not set.
+// `max_cost` is the traversal cost shown as red.
+pub struct DebugSettings {
+    pub mode: DebugMode,
+    pub distance: Option<f64>,
+    pub max_cost: f64,
+}
+
+// Ambient occlusion instead of the light, the fraction of `samples` rays
+// per hit that get `distance` (the size of the scene if not set) far.
+pub struct AoSettings {
+    pub distance: Option<f64>,
+    pub samples: u32,
+    pub bake: Option<BakeSettings>,
+}
+
+// Ambient occlusion of every vertex of `model` from `samples` rays, written
+// to `output` as OBJ or PLY with vertex colors. The vertices are taken as
+// they are in the file, so the model should be placed without a transform.
+pub struct BakeSettings {
+    pub model: String,
+    pub output: String,
+    pub samples: u32,
+}
+
+pub struct CheckpointSettings {
+    pub path: String,
+    pub interval: Option<f64>,
+}
+
+impl RenderSettings {
+    pub fn shutter_time(&self, u: f64) -> f64 {
+        self.shutter_open + u * (self.shutter_close - self.shutter_open)
+    }
+
+    // Output `path` of `frame`, numbered before the extension when there is
+    // more than one frame.
+    pub fn frame_path(&self, path: &str, frame: u32) -> String {
+        if self.frames <= 1 {
+            return path.to_owned();
+        }
+
+        let path = std::path::Path::new(path);
+        let stem = path
+            .file_stem()
+            .map_or(String::new(), |s| s.to_string_lossy().into_owned());
+
+        let name = match path.extension() {
+            Some(extension) => format!("{}_{:04}.{}", stem, frame, extension.to_string_lossy()),
+            None => format!("{}_{:04}", stem, frame),
+        };
+
+        path.with_file_name(name).to_string_lossy().into_owned()
+    }
+}
+
+pub fn read_vector(data: &serde_json::Value) -> Vector3<f64> {
+    Vector3::<f64>::new(
+        data["x"].as_f64().unwrap(),
+        data["y"].as_f64().unwrap(),
+        data["z"].as_f64().unwrap(),
+    )
+}
+
+pub fn read_color(data: &serde_json::Value) -> Vector3<f64> {
+    Vector3::<f64>::new(
+        data["r"].as_f64().unwrap(),
+        data["g"].as_f64().unwrap(),
+        data["b"].as_f64().unwrap(),
+    )
+}
+
+fn create_aov_settings(
+    data: &serde_json::Value,
+    output: &str,
+) -> Result<AovSettings, Box<dyn Error>> {
+    let aovs = match data["names"].as_array() {
+        Some(names) => names
+            .iter()
+            .map(|name| {
+                let name = name.as_str().unwrap_or("");
+                Aov::from_name(name).ok_or_else(|| format!("Unknown AOV \"{}\"", name))
+            })
+            .collect::<Result<_, _>>()?,
+        None => aov::ALL.to_vec(),
+    };
+
+    let output = data["output"]
+        .as_str()
+        .map_or_else(|| derived_path(output, "aovs.exr"), |v| v.to_owned());
+
+    if !output.contains("{}") && !output.ends_with(".exr") {
+        return Err("Multi-layer AOV output needs to be an .exr file".into());
+    }
+
+    Ok(AovSettings { aovs, output })
+}
+
+fn create_ao_settings(
+    data: &serde_json::Value,
+    model: Option<&str>,
+) -> Result<AoSettings, Box<dyn Error>> {
+    let bake = match data.get("bake") {
+        Some(bake) => Some(BakeSettings {
+            model: bake["model"]
+                .as_str()
+                .or(model)
+                .ok_or("Baking needs a model")?
+                .to_owned(),
+            output: match bake["output"].as_str() {
+                Some(output) if output.ends_with(".obj") || output.ends_with(".ply") => {
+                    output.to_owned()
+                }
+                _ => return Err("Baking needs an .obj or .ply output".into()),
+            },
+            samples: bake["samples"].as_u64().unwrap_or(256).max(1) as u32,
+        }),
+        None => None,
+    };
+
+    Ok(AoSettings {
+        distance: data["distance"].as_f64(),
+        samples: data["samples"].as_u64().unwrap_or(1).max(1) as u32,
+        bake,
+    })
+}
+
+// Either just the mode name or an object with the mode and its settings.
+fn create_debug_settings(data: &serde_json::Value) -> Result<DebugSettings, Box<dyn Error>> {
+    let name = data
+        .as_str()
+        .or_else(|| data["mode"].as_str())
+        .ok_or("Debug settings need a mode")?;
+
+    Ok(DebugSettings {
+        mode: DebugMode::from_name(name)
+            .ok_or_else(|| format!("Unknown debug mode \"{}\"", name))?,
+        distance: data["distance"].as_f64(),
+        max_cost: data["max_cost"].as_f64().unwrap_or(100.0),
+    })
+}
+
+fn create_settings(data: &serde_json::Value) -> Result<RenderSettings, Box<dyn Error>> {
+    let output = data["output"].as_str().unwrap_or("output.png").to_owned();
+    let spp = data["adaptive"]["max_spp"]
+        .as_u64()
+        .or_else(|| data["spp"].as_u64())
+        .unwrap_or(256) as u32;
+
+    Ok(RenderSettings {
+        width: data["width"].as_u64().unwrap_or(800) as u32,
+        height: data["height"].as_u64().unwrap_or(600) as u32,
+        spp,
+        depth: data["depth"].as_i64().unwrap_or(3) as i32,
+        shutter_open: data["shutter"]["open"].as_f64().unwrap_or(0.0),
+        shutter_close: data["shutter"]["close"].as_f64().unwrap_or(0.0),
+        frames: 1 + data["objects"].as_array().map_or(0, |objects| {
+            objects
+                .iter()
+                .filter_map(|o| o["frames"].as_array())
+                .map(|frames| frames.len())
+                .max()
+                .unwrap_or(0)
+        }) as u32,
+        progressive: data["progressive"]
+            .as_object()
+            .map(|progressive| ProgressiveSettings {
+                pass_spp: progressive["pass_spp"].as_u64().unwrap_or(1) as u32,
+                snapshot_interval: progressive
+                    .get("snapshot_interval")
+                    .and_then(|v| v.as_f64()),
+                time_budget: progressive.get("time_budget").and_then(|v| v.as_f64()),
+            }),
+        checkpoint: data["checkpoint"]
+            .as_object()
+            .map(|checkpoint| CheckpointSettings {
+                path: checkpoint
+                    .get("path")
+                    .and_then(|v| v.as_str())
+                    .map_or_else(|| output.clone() + ".checkpoint", |v| v.to_owned()),
+                interval: checkpoint.get("interval").and_then(|v| v.as_f64()),
+            }),
+        adaptive: data["adaptive"]
+            .as_object()
+            .map(|adaptive| AdaptiveSettings {
+                threshold: adaptive
+                    .get("threshold")
+                    .and_then(|v| v.as_f64())
+                    .unwrap_or(0.02),
+                min_spp: adaptive
+                    .get("min_spp")
+                    .and_then(|v| v.as_u64())
+                    .map_or(16.min(spp), |v| v as u32),
+                pass_spp: adaptive
+                    .get("pass_spp")
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or(8) as u32,
+                per_tile: adaptive
+                    .get("per_tile")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+                sample_map: adaptive
+                    .get("sample_map")
+                    .and_then(|v| v.as_str())
+                    .map(|v| v.to_owned()),
+            }),
+        denoise: data["denoise"].as_object().map(|denoise| {
+            let f = |key: &str, default: f64| {
+                denoise.get(key).and_then(|v| v.as_f64()).unwrap_or(default)
+            };
+
+            DenoiseSettings {
+                iterations: denoise
+                    .get("iterations")
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or(5) as u32,
+                sigma_color: f("sigma_color", 2.0),
+                sigma_normal: f("sigma_normal", 64.0),
+                sigma_depth: f("sigma_depth", 0.05),
+                sigma_albedo: f("sigma_albedo", 0.1),
+                output: denoise
+                    .get("output")
+                    .and_then(|v| v.as_str())
+                    .map_or_else(|| derived_path(&output, "denoised.png"), |v| v.to_owned()),
+            }
+        }),
+        aovs: match data.get("aovs") {
+            Some(aovs) => Some(create_aov_settings(aovs, &output)?),
+            None => None,
+        },
+        profile: data["profile"].as_object().map(|profile| ProfileSettings {
+            heatmap: profile
+                .get("heatmap")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_owned()),
+        }),
+        debug: match data.get("debug") {
+            Some(debug) => Some(create_debug_settings(debug)?),
+            None => None,
+        },
+        ao: match data.get("ao") {
+            Some(ao) => Some(create_ao_settings(ao, data["model"].as_str())?),
+            None => None,
+        },
+        integrator: match data["integrator"].as_str().unwrap_or("path") {
+            "path" => Integrator::Path,
+            "bdpt" => Integrator::Bidirectional,
+            name => return Err(format!("Unknown integrator \"{}\"", name).into()),
+        },
+        clamp_direct: data["clamp"]["direct"].as_f64(),
+        clamp_indirect: data["clamp"]["indirect"].as_f64(),
+        regularize: data["regularize"].as_object().map(|regularize| {
+            regularize
+                .get("roughness")
+                .and_then(|v| v.as_f64())
+                .unwrap_or(0.3)
+        }),
+        output,
+    })
+}
+
+// derived_path("image.png", "denoised.png") -> "image.denoised.png"
+fn derived_path(output: &str, extension: &str) -> String {
+    std::path::Path::new(output)
+        .with_extension(extension)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn read_look_at(data: &serde_json::Value) -> Isometry3<f64> {
+    let origin: Point3<f64> = read_vector(&data["origin"]).into();
+    let direction = (read_vector(&data["target"]) - origin.coords).normalize();
+    let up = if data["up"].is_null() {
+        Vector3::y()
+    } else {
+        read_vector(&data["up"])
+    };
+
+    look_at(&origin, &direction, &up)
+}
+
+// Keyframes placed by `read`, each at a finite "time".
+fn read_keyframes(
+    data: &[serde_json::Value],
+    read: impl Fn(&serde_json::Value) -> Isometry3<f64>,
+) -> Result<AnimatedIsometry, Box<dyn Error>> {
+    if data.is_empty() {
+        return Err("Keyframes can't be empty".into());
+    }
+
+    let mut keyframes = vec![];
+
+    for k in data {
+        let time = k["time"]
+            .as_f64()
+            .filter(|t| t.is_finite())
+            .ok_or("Keyframes need a finite time")?;
+
+        keyframes.push(Keyframe {
+            time,
+            isometry: read(k),
+        });
+    }
+
+    Ok(AnimatedIsometry::new(keyframes))
+}
+
+fn read_transform(data: &serde_json::Value) -> Isometry3<f64> {
+    let translation = if data["translation"].is_null() {
+        Vector3::zeros()
+    } else {
+        read_vector(&data["translation"])
+    };
+
+    let rotation = if data["rotation"].is_null() {
+        UnitQuaternion::identity()
+    } else {
+        UnitQuaternion::from_axis_angle(
+            &Unit::new_normalize(read_vector(&data["rotation"]["axis"])),
+            data["rotation"]["angle"].as_f64().unwrap().to_radians(),
+        )
+    };
+
+    Isometry3::from_parts(Translation3::from(translation), rotation)
+}
+
+// Isometry of `read_transform` after a "scale", either a number or a
+// vector of per axis factors.
+fn read_affine(data: &serde_json::Value) -> Affine3<f64> {
+    let scale = match data["scale"].as_f64() {
+        Some(s) => Vector3::repeat(s),
+        None if data["scale"].is_object() => read_vector(&data["scale"]),
+        None => Vector3::repeat(1.0),
+    };
+
+    Affine3::from_matrix_unchecked(
+        read_transform(data).to_homogeneous() * Matrix4::new_nonuniform_scaling(&scale),
+    )
+}
+
+fn create_bvh_settings(data: &serde_json::Value) -> Result<BuildSettings, Box<dyn Error>> {
+    let default = BuildSettings::default();
+
+    Ok(BuildSettings {
+        builder: match data["builder"].as_str().unwrap_or("sah") {
+            "sah" => Builder::Sah,
+            "median" => Builder::Median,
+            name => return Err(format!("Unknown BVH builder \"{}\"", name).into()),
+        },
+        bins: data["bins"].as_u64().map_or(default.bins, |v| v as usize),
+        traversal_cost: data["traversal_cost"]
+            .as_f64()
+            .unwrap_or(default.traversal_cost),
+        intersection_cost: data["intersection_cost"]
+            .as_f64()
+            .unwrap_or(default.intersection_cost),
+        max_leaf_size: data["max_leaf_size"]
+            .as_u64()
+            .map_or(default.max_leaf_size, |v| v as usize),
+        compare: data["compare"].as_bool().unwrap_or(false),
+        stats: data["stats"].as_bool().unwrap_or(false),
+        cache: data["cache"].as_bool().unwrap_or(false),
+        refit_threshold: data["refit_threshold"].as_f64(),
+        width: match data["width"].as_u64().unwrap_or(2) {
+            width @ (2 | 4 | 8) => width as usize,
+            width => return Err(format!("BVH width must be 2, 4 or 8, not {}", width).into()),
+        },
+        benchmark: data["benchmark"].as_bool().unwrap_or(false),
+    })
+}
+
+// Every entry of "instances" places the model once, without it the object
+// itself is the only placement. Models are loaded once and shared by all of
+// their instances. Models with "frames", the OBJ files of the following
+// frames of a vertex animation, are moved in place and belong to their
+// object alone.
+fn create_objects(
+    data: &serde_json::Value,
+    bvh: &BuildSettings,
+    models: &mut HashMap<String, Arc<dyn Intersect>>,
+) -> Result<Vec<Box<dyn Intersect>>, Box<dyn Error>> {
+    let path = data["model"].as_str().ok_or("Object needs a model")?;
+
+    let model = match (data["frames"].as_array(), models.get(path)) {
+        (Some(frames), _) => {
+            if !data["instances"].is_null() {
+                return Err("Animated objects can't be instanced".into());
+            }
+
+            let frames = frames
+                .iter()
+                .map(|f| f.as_str().map(|f| f.to_owned()))
+                .collect::<Option<Vec<_>>>()
+                .ok_or("Frames need to be OBJ paths")?;
+
+            Arc::new(mesh::load_animated_model(path, frames, bvh)?) as Arc<dyn Intersect>
+        }
+        (None, Some(model)) => model.clone(),
+        (None, None) => {
+            let model: Arc<dyn Intersect> = Arc::new(mesh::load_model_bvh(path, bvh)?);
+            models.insert(path.to_owned(), model.clone());
+            model
+        }
+    };
+
+    let motion = match data["keyframes"].as_array() {
+        Some(keyframes) => Some(read_keyframes(keyframes, read_transform)?),
+        None => None,
+    };
+
+    let placements = match data["instances"].as_array() {
+        Some(instances) => instances.iter().collect(),
+        None => vec![data],
+    };
+
+    Ok(placements
+        .into_iter()
+        .map(|placement| {
+            let brdf = if placement["material"].is_null() {
+                None
+            } else {
+                Some(mesh::read_material(&placement["material"]))
+            };
+
+            let instance = Box::new(Instance::new(model.clone(), read_affine(placement), brdf));
+
+            match &motion {
+                Some(motion) => {
+                    Box::new(MovingObject::new(instance, motion.clone())) as Box<dyn Intersect>
+                }
+                None => instance,
+            }
+        })
+        .collect())
+}
+
+fn create_camera(
+    data: &serde_json::Value,
+    settings: &RenderSettings,
+) -> Result<Box<dyn Camera>, Box<dyn Error>> {
+    let motion = match data["keyframes"].as_array() {
+        Some(keyframes) => read_keyframes(keyframes, read_look_at)?,
+        None => AnimatedIsometry::fixed(read_look_at(data)),
+    };
+
+    let img_dimensions = Vector2::<u32>::new(settings.width, settings.height);
+
+    match data["type"].as_str().unwrap_or("perspective") {
+        "perspective" => Ok(Box::new(PerspectiveCamera::new(
+            motion,
+            img_dimensions,
+            data["fov"].as_f64().unwrap(),
+        ))),
+
+        "orthographic" => Ok(Box::new(OrthographicCamera::new(
+            motion,
+            img_dimensions,
+            data["scale"].as_f64().unwrap(),
+        ))),
+
+        "fisheye" => Ok(Box::new(FisheyeCamera::new(
+            motion,
+            img_dimensions,
+            data["fov"].as_f64().unwrap_or(180.0),
+        ))),
+
+        "equirectangular" => Ok(Box::new(EquirectangularCamera::new(motion, img_dimensions))),
+
+        name => Err(format!("Unknown camera type \"{}\"", name).into()),
+    }
+}
+
+fn create_filter(data: &serde_json::Value) -> Result<Arc<dyn Filter>, Box<dyn Error>> {
+    match data["type"].as_str().unwrap_or("box") {
+        "box" => Ok(Arc::new(BoxFilter {
+            radius: data["radius"].as_f64().unwrap_or(0.5),
+        })),
+
+        "tent" => Ok(Arc::new(TentFilter {
+            radius: data["radius"].as_f64().unwrap_or(1.0),
+        })),
+
+        "gaussian" => Ok(Arc::new(GaussianFilter {
+            radius: data["radius"].as_f64().unwrap_or(1.5),
+            alpha: data["alpha"].as_f64().unwrap_or(2.0),
+        })),
+
+        "mitchell" => Ok(Arc::new(MitchellFilter {
+            radius: data["radius"].as_f64().unwrap_or(2.0),
+            b: data["b"].as_f64().unwrap_or(1.0 / 3.0),
+            c: data["c"].as_f64().unwrap_or(1.0 / 3.0),
+        })),
+
+        "lanczos" => Ok(Arc::new(LanczosFilter {
+            radius: data["radius"].as_f64().unwrap_or(3.0),
+            tau: data["tau"].as_f64().unwrap_or(3.0),
+        })),
+
+        name => Err(format!("Unknown filter type \"{}\"", name).into()),
+    }
+}
+
+fn create_sampler(
+    data: &serde_json::Value,
+    settings: &RenderSettings,
+) -> Result<Box<dyn Sampler>, Box<dyn Error>> {
+    let seed = data["seed"].as_u64().unwrap_or(0) as u32;
+
+    match data["type"].as_str().unwrap_or("independent") {
+        "independent" => Ok(Box::new(IndependentSampler::new(seed))),
+        "stratified" => Ok(Box::new(StratifiedSampler::new(seed, settings.spp))),
+        "halton" => Ok(Box::new(HaltonSampler::new(seed))),
+        "sobol" => Ok(Box::new(SobolSampler::new(seed))),
+        name => Err(format!("Unknown sampler type \"{}\"", name).into()),
+    }
+}
+
+fn create_light(data: &serde_json::Value) -> Result<Box<dyn Light>, Box<dyn Error>> {
+    match data["type"].as_str().unwrap_or("disk") {
+        "disk" => Ok(Box::new(DiskLight {
+            pos: read_vector(&data["pos"]).into(),
+            color: read_color(&data["color"]),
+            power: data["power"].as_f64().unwrap(),
+            radius: data["radius"].as_f64().unwrap(),
+            normal: read_vector(&data["normal"]).normalize(),
+        })),
+
+        name => Err(format!("Unknown light type \"{}\"", name).into()),
+    }
+}
+
+// FNV-1a, stable across runs and platforms unlike the std hashers.
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |h, &b| (h ^ b as u64).wrapping_mul(0x100_0000_01b3))
+}
+
+pub const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+// Hash of everything that changes the converged image: the scene
+// description without the settings that only control how long and where
+// the render goes or what is done besides it, and the contents of the
+// referenced models. Debug modes change the radiance, so `debug` stays.
+fn scene_hash(data: &serde_json::Value) -> Result<u64, Box<dyn Error>> {
+    let mut canonical = data.clone();
+
+    if let Some(map) = canonical.as_object_mut() {
+        for key in &[
+            "spp",
+            "output",
+            "progressive",
+            "checkpoint",
+            "adaptive",
+            "denoise",
+            "profile",
+            "bvh",
+            "aovs",
+        ] {
+            map.remove(*key);
+        }
+
+        if let Some(ao) = map.get_mut("ao").and_then(|ao| ao.as_object_mut()) {
+            ao.remove("bake");
+        }
+    }
+
+    let mut hash = fnv1a(FNV_OFFSET, canonical.to_string().as_bytes());
+
+    let objects = data["objects"].as_array().cloned().unwrap_or_default();
+    let models = std::iter::once(&data["model"])
+        .chain(objects.iter().map(|o| &o["model"]))
+        .filter_map(|m| m.as_str());
+
+    for model in models {
+        hash = fnv1a(hash, &std::fs::read(model)?);
+        hash = fnv1a(hash, &std::fs::read(model.to_owned() + ".json")?);
+    }
+
+    Ok(hash)
+}
+
+pub fn load_scene(path: &str) -> Result<Scene, Box<dyn Error>> {
+    let data: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+
+    let settings = create_settings(&data)?;
+
+    // Every frame is rendered from scratch into its own image.
+    if settings.frames > 1 && (settings.progressive.is_some() || settings.checkpoint.is_some()) {
+        return Err("Animated scenes can't be rendered progressively or checkpointed".into());
+    }
+
+    let bvh = create_bvh_settings(&data["bvh"])?;
+    let camera = create_camera(&data["camera"], &settings)?;
+    let filter = create_filter(&data["filter"])?;
+    let sampler = create_sampler(&data["sampler"], &settings)?;
+
+    let mut lights: Vec<Box<dyn Light>> = vec![];
+    for light in data["lights"]
+        .as_array()
+        .ok_or("Scene needs a list of lights")?
+    {
+        lights.push(create_light(light)?);
+    }
+
+    let mut objects: Vec<Box<dyn Intersect>> = vec![];
+    let mut models = HashMap::new();
+
+    if let Some(model) = data["model"].as_str() {
+        objects.push(Box::new(mesh::load_model_bvh(model, &bvh)?));
+    }
+
+    if let Some(entries) = data["objects"].as_array() {
+        for entry in entries {
+            objects.extend(create_objects(entry, &bvh, &mut models)?);
+        }
+    }
+
+    if objects.is_empty() {
+        return Err("Scene needs a model or a list of objects".into());
+    }
+
+    Ok(Scene {
+        obj: Box::new(ObjectTree::new(objects, &bvh)),
+        lights,
+        camera,
+        filter,
+        sampler,
+        settings,
+        description: data,
+        hash: OnceLock::new(),
+    })
+}
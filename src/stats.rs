@@ -0,0 +1,142 @@
+extern crate nalgebra as na;
+use na::Vector3;
+
+use std::cell::Cell;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// What rendering spends its time on. Every thread counts into its own
+// copy, `flush` adds it to the totals, so counting needs no locking. Hot
+// loops count into locals and add them once per call.
+#[derive(Clone, Copy)]
+pub struct Counters {
+    pub primary_rays: u64,
+    pub secondary_rays: u64,
+    pub shadow_rays: u64,
+    pub nodes_visited: u64,
+    pub primitive_tests: u64,
+    pub path_vertices: u64,
+}
+
+const ZERO: Counters = Counters {
+    primary_rays: 0,
+    secondary_rays: 0,
+    shadow_rays: 0,
+    nodes_visited: 0,
+    primitive_tests: 0,
+    path_vertices: 0,
+};
+
+impl Counters {
+    fn add(&mut self, b: &Counters) {
+        self.primary_rays += b.primary_rays;
+        self.secondary_rays += b.secondary_rays;
+        self.shadow_rays += b.shadow_rays;
+        self.nodes_visited += b.nodes_visited;
+        self.primitive_tests += b.primitive_tests;
+        self.path_vertices += b.path_vertices;
+    }
+
+    // Traversal work, what the heatmap shows.
+    pub fn cost(&self) -> u64 {
+        self.nodes_visited + self.primitive_tests
+    }
+
+    pub fn rays(&self) -> u64 {
+        self.primary_rays + self.secondary_rays + self.shadow_rays
+    }
+}
+
+thread_local! {
+    static LOCAL: Cell<Counters> = const { Cell::new(ZERO) };
+}
+
+static TOTALS: Mutex<Counters> = Mutex::new(ZERO);
+static BUILD_TIME: Mutex<Duration> = Mutex::new(Duration::ZERO);
+
+pub fn count(f: impl FnOnce(&mut Counters)) {
+    LOCAL.with(|local| {
+        let mut counters = local.get();
+        f(&mut counters);
+        local.set(counters);
+    });
+}
+
+pub fn count_traversal(nodes: usize, primitives: usize) {
+    count(|c| {
+        c.nodes_visited += nodes as u64;
+        c.primitive_tests += primitives as u64;
+    });
+}
+
+// Counts of the current thread since its last flush.
+pub fn local() -> Counters {
+    LOCAL.with(Cell::get)
+}
+
+pub fn flush() {
+    let counters = LOCAL.with(|local| local.replace(ZERO));
+    TOTALS.lock().unwrap().add(&counters);
+}
+
+pub fn add_build_time(time: Duration) {
+    *BUILD_TIME.lock().unwrap() += time;
+}
+
+pub fn print(render_time: Duration) {
+    let c = *TOTALS.lock().unwrap();
+    let per_ray = |n: u64| n as f64 / c.rays().max(1) as f64;
+
+    println!(
+        "Rays: {} primary, {} secondary, {} shadow, {:.2} Mrays/s",
+        c.primary_rays,
+        c.secondary_rays,
+        c.shadow_rays,
+        c.rays() as f64 / render_time.as_secs_f64() / 1.0e6
+    );
+    println!(
+        "Traversal: {} nodes visited ({:.2} per ray), {} primitive tests ({:.2} per ray)",
+        c.nodes_visited,
+        per_ray(c.nodes_visited),
+        c.primitive_tests,
+        per_ray(c.primitive_tests)
+    );
+    println!(
+        "Average path length: {:.2}",
+        c.path_vertices as f64 / c.primary_rays.max(1) as f64
+    );
+    println!(
+        "BVH build time: {:?}, render time: {:?}",
+        *BUILD_TIME.lock().unwrap(),
+        render_time
+    );
+}
+
+// Blue for 0 over green to red for 1.
+pub fn ramp(t: f64) -> Vector3<f64> {
+    let t = t.clamp(0.0, 1.0);
+
+    Vector3::new(
+        (2.0 * t - 1.0).max(0.0),
+        1.0 - (2.0 * t - 1.0).abs(),
+        (1.0 - 2.0 * t).max(0.0),
+    )
+}
+
+// Average cost per sample of every pixel, relative to the most expensive
+// pixel on `ramp`.
+pub fn heatmap(width: u32, height: u32, costs: &[f64]) -> image::RgbImage {
+    let max = costs.iter().cloned().fold(0.0, f64::max).max(1.0);
+
+    let mut im = image::RgbImage::new(width, height);
+
+    for (x, y, pixel) in im.enumerate_pixels_mut() {
+        let c = ramp(costs[(y * width + x) as usize] / max);
+
+        for k in 0..3 {
+            pixel[k] = (c[k] * 255.0).round() as u8;
+        }
+    }
+
+    im
+}
@@ -0,0 +1,527 @@
+extern crate nalgebra as na;
+use na::Vector3;
+
+use std::time::Instant;
+
+use crate::bvh::{self, Bounds, Tree, MAX_DEPTH};
+use crate::primitive::{triangle_delta_t, IntersectionRecord, MeshTriangle, Primitive, Shear};
+use crate::ray::Ray;
+use crate::stats;
+
+// Marks unused child slots.
+const EMPTY: u32 = u32::MAX;
+
+// Widest tree the traversal stack is sized for.
+const MAX_WIDTH: usize = 8;
+
+// Every wide node pushes at most W - 1 more entries than it pops, and the
+// collapsed tree is no deeper than the binary one.
+const STACK_SIZE: usize = (MAX_WIDTH - 1) * MAX_DEPTH + 1;
+
+// Child boxes and leaf triangles are stored as structures of arrays, one
+// lane per child or triangle, and all lanes are tested in one loop over
+// the arrays. The loops have no branches, misses are masks selecting
+// infinite distances, so the compiler turns them into vector instructions.
+#[derive(Clone)]
+struct WideNode<const W: usize> {
+    min: [[f64; W]; 3],
+    max: [[f64; W]; 3],
+    // Node index of internal children, first packet of leaf children.
+    child: [u32; W],
+    // Number of packets of leaf children, 0 for internal children.
+    packets: [u32; W],
+}
+
+impl<const W: usize> WideNode<W> {
+    fn empty() -> WideNode<W> {
+        WideNode {
+            min: [[0.0; W]; 3],
+            max: [[0.0; W]; 3],
+            child: [EMPTY; W],
+            packets: [0; W],
+        }
+    }
+
+    // Entry distances of the ray into the child boxes, infinite for
+    // children it misses within `[ray.t_min, t_max]`.
+    fn entries(&self, ray: &Ray, inv: &Vector3<f64>, t_max: f64) -> [f64; W] {
+        let o = ray.origin.coords;
+        let mut entries = [0.0; W];
+
+        for (k, entry) in entries.iter_mut().enumerate() {
+            let tx0 = (self.min[0][k] - o.x) * inv.x;
+            let tx1 = (self.max[0][k] - o.x) * inv.x;
+            let ty0 = (self.min[1][k] - o.y) * inv.y;
+            let ty1 = (self.max[1][k] - o.y) * inv.y;
+            let tz0 = (self.min[2][k] - o.z) * inv.z;
+            let tz1 = (self.max[2][k] - o.z) * inv.z;
+
+            let tmin = max(
+                max(min(tx0, tx1), min(ty0, ty1)),
+                max(min(tz0, tz1), ray.t_min),
+            );
+            let tmax = min(min(max(tx0, tx1), max(ty0, ty1)), min(max(tz0, tz1), t_max));
+
+            *entry = if tmin <= tmax { tmin } else { f64::INFINITY };
+        }
+
+        entries
+    }
+}
+
+// Up to W triangles of one leaf, padded with copies of the last one.
+#[derive(Clone)]
+struct TrianglePacket<const W: usize> {
+    p: [[[f64; W]; 3]; 3],
+    // Index of the triangle in `WideTree::triangles`.
+    index: [u32; W],
+}
+
+impl<const W: usize> TrianglePacket<W> {
+    fn new(triangles: &[MeshTriangle], first: usize) -> TrianglePacket<W> {
+        let mut packet = TrianglePacket {
+            p: [[[0.0; W]; 3]; 3],
+            index: [0; W],
+        };
+
+        for k in 0..W {
+            let index = first + k.min(triangles.len() - first - 1);
+            let positions = triangles[index].positions();
+
+            for (p, position) in packet.p.iter_mut().zip(positions.iter()) {
+                for axis in 0..3 {
+                    p[axis][k] = position[axis];
+                }
+            }
+
+            packet.index[k] = index as u32;
+        }
+
+        packet
+    }
+
+    // Watertight test on all lanes, the same operations as
+    // `primitive::intersect_triangle`.
+    fn intersect(&self, ray: &Ray, shear: &Shear) -> PacketHits<W> {
+        let [kx, ky, kz] = shear.axes;
+        let (ox, oy, oz) = (ray.origin[kx], ray.origin[ky], ray.origin[kz]);
+
+        let mut hits = PacketHits {
+            t: [0.0; W],
+            b: [[0.0; W]; 3],
+        };
+
+        // The axes are picked once, the lanes then read plain arrays.
+        let [a, b, c] = self.p.each_ref().map(|p| [&p[kx], &p[ky], &p[kz]]);
+
+        for k in 0..W {
+            let (akx, aky, akz) = (a[0][k] - ox, a[1][k] - oy, a[2][k] - oz);
+            let (bkx, bky, bkz) = (b[0][k] - ox, b[1][k] - oy, b[2][k] - oz);
+            let (ckx, cky, ckz) = (c[0][k] - ox, c[1][k] - oy, c[2][k] - oz);
+
+            let ax = akx - shear.s.x * akz;
+            let ay = aky - shear.s.y * akz;
+            let bx = bkx - shear.s.x * bkz;
+            let by = bky - shear.s.y * bkz;
+            let cx = ckx - shear.s.x * ckz;
+            let cy = cky - shear.s.y * ckz;
+
+            let u = cx * by - cy * bx;
+            let v = ax * cy - ay * cx;
+            let w = bx * ay - by * ax;
+
+            let det = u + v + w;
+
+            let (az, bz, cz) = (shear.s.z * akz, shear.s.z * bkz, shear.s.z * ckz);
+            let t = (u * az + v * bz + w * cz) / det;
+
+            let delta_t =
+                triangle_delta_t([ax, bx, cx], [ay, by, cy], [az, bz, cz], [u, v, w], det);
+
+            // Non short circuiting, so the lanes need no branches.
+            let miss = (((u < 0.0) | (v < 0.0) | (w < 0.0)) & ((u > 0.0) | (v > 0.0) | (w > 0.0)))
+                | (det == 0.0)
+                | !((t >= ray.t_min) & (t <= ray.t_max))
+                | (t <= delta_t);
+
+            hits.t[k] = if miss { f64::INFINITY } else { t };
+            hits.b[0][k] = u / det;
+            hits.b[1][k] = v / det;
+            hits.b[2][k] = w / det;
+        }
+
+        hits
+    }
+}
+
+// Distances and barycentric coordinates of the triangles of a packet,
+// infinite distances for the lanes the ray misses.
+struct PacketHits<const W: usize> {
+    t: [f64; W],
+    b: [[f64; W]; 3],
+}
+
+// Triangle BVH with W children per node, collapsed from a binary `Tree`.
+// Leaves are packets of W triangles.
+pub struct WideTree<const W: usize> {
+    nodes: Vec<WideNode<W>>,
+    packets: Vec<TrianglePacket<W>>,
+    triangles: Vec<MeshTriangle>,
+    bounds: Option<Bounds>,
+}
+
+impl<const W: usize> WideTree<W> {
+    const WIDTH_CHECK: () = assert!(W >= 2 && W <= MAX_WIDTH);
+
+    pub fn new(tree: &Tree<MeshTriangle>) -> WideTree<W> {
+        let () = Self::WIDTH_CHECK;
+
+        let mut wide = WideTree {
+            nodes: vec![],
+            packets: vec![],
+            triangles: tree.primitives.clone(),
+            bounds: tree.nodes.first().map(|n| n.bounds.clone()),
+        };
+
+        if !tree.nodes.is_empty() {
+            wide.collapse(tree, 0);
+        }
+
+        wide
+    }
+
+    // Adds the wide node replacing binary node `index` and the nodes below
+    // it. Starting from the children of `index`, the internal child with
+    // the largest surface area is opened until there are W children.
+    fn collapse(&mut self, tree: &Tree<MeshTriangle>, index: usize) -> u32 {
+        let binary = &tree.nodes;
+
+        let mut children = if binary[index].count > 0 {
+            vec![index]
+        } else {
+            vec![index + 1, binary[index].offset as usize]
+        };
+
+        while children.len() < W {
+            let largest = children
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| binary[**c].count == 0)
+                .max_by(|(_, a), (_, b)| {
+                    binary[**a]
+                        .bounds
+                        .surface_area()
+                        .partial_cmp(&binary[**b].bounds.surface_area())
+                        .unwrap()
+                })
+                .map(|(k, c)| (k, *c));
+
+            match largest {
+                Some((k, c)) => {
+                    children[k] = c + 1;
+                    children.push(binary[c].offset as usize);
+                }
+                None => break,
+            }
+        }
+
+        let node_index = self.nodes.len();
+        self.nodes.push(WideNode::empty());
+
+        for (k, c) in children.into_iter().enumerate() {
+            let node = &binary[c];
+
+            let (child, packets) = if node.count > 0 {
+                let first = self.packets.len();
+                let start = node.offset as usize;
+                let end = start + node.count as usize;
+
+                for i in (start..end).step_by(W) {
+                    self.packets
+                        .push(TrianglePacket::new(&self.triangles[..end], i));
+                }
+
+                (first as u32, (self.packets.len() - first) as u32)
+            } else {
+                (self.collapse(tree, c), 0)
+            };
+
+            let wide = &mut self.nodes[node_index];
+
+            for axis in 0..3 {
+                wide.min[axis][k] = node.bounds.min[axis];
+                wide.max[axis][k] = node.bounds.max[axis];
+            }
+
+            wide.child[k] = child;
+            wide.packets[k] = packets;
+        }
+
+        node_index as u32
+    }
+
+    // Index, distance and barycentric coordinates of the closest triangle
+    // hit, or of any triangle hit with `any_hit`. Counts the wide nodes
+    // visited and the triangle lanes tested, padding included.
+    fn traverse(
+        &self,
+        ray: &Ray,
+        any_hit: bool,
+        visits: &mut usize,
+        tests: &mut usize,
+    ) -> Option<(usize, f64, [f64; 3])> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inv = Vector3::repeat(1.0).component_div(&ray.direction);
+        let shear = Shear::new(&ray.direction);
+
+        let mut closest = None;
+        let mut clipped = *ray;
+
+        // Entries are (child, packets, entry distance), the root being an
+        // internal child.
+        let mut stack = [(0, 0, 0.0); STACK_SIZE];
+        stack[0] = (0, 0, ray.t_min);
+        let mut len = 1;
+
+        while len > 0 {
+            len -= 1;
+            let (child, packets, entry) = stack[len];
+
+            if entry > clipped.t_max {
+                continue;
+            }
+
+            if packets > 0 {
+                for packet in &self.packets[child as usize..(child + packets) as usize] {
+                    let hits = packet.intersect(&clipped, &shear);
+                    *tests += W;
+
+                    for (k, &t) in hits.t.iter().enumerate() {
+                        if t == f64::INFINITY {
+                            continue;
+                        }
+
+                        let hit = (
+                            packet.index[k] as usize,
+                            t,
+                            [hits.b[0][k], hits.b[1][k], hits.b[2][k]],
+                        );
+
+                        if any_hit {
+                            return Some(hit);
+                        }
+
+                        if t < clipped.t_max || closest.is_none() {
+                            clipped.t_max = t;
+                            closest = Some(hit);
+                        }
+                    }
+                }
+
+                continue;
+            }
+
+            *visits += 1;
+            let node = &self.nodes[child as usize];
+            let entries = node.entries(ray, &inv, clipped.t_max);
+
+            // Hit children are sorted farthest first so the nearest is
+            // popped first.
+            let start = len;
+
+            for (k, entry) in entries.iter().enumerate() {
+                if node.child[k] != EMPTY && *entry != f64::INFINITY {
+                    stack[len] = (node.child[k], node.packets[k], *entry);
+                    len += 1;
+                }
+            }
+
+            for i in start + 1..len {
+                let mut j = i;
+
+                while j > start && stack[j - 1].2 < stack[j].2 {
+                    stack.swap(j - 1, j);
+                    j -= 1;
+                }
+            }
+        }
+
+        closest
+    }
+}
+
+impl<const W: usize> Primitive for WideTree<W> {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionRecord> {
+        let (mut visits, mut tests) = (0, 0);
+        let hit = self.traverse(ray, false, &mut visits, &mut tests);
+
+        stats::count_traversal(visits, tests);
+
+        hit.map(|(i, t, b)| self.triangles[i].record(t, &b))
+    }
+
+    fn occluded(&self, ray: &Ray, t_max: f64) -> bool {
+        let ray = Ray {
+            t_max: t_max.min(ray.t_max),
+            ..*ray
+        };
+
+        let (mut visits, mut tests) = (0, 0);
+        let hit = self.traverse(&ray, true, &mut visits, &mut tests);
+
+        stats::count_traversal(visits, tests);
+
+        hit.is_some()
+    }
+
+    fn bounds(&self) -> Option<Bounds> {
+        self.bounds.clone()
+    }
+}
+
+// Prints the closest hit rays per second through `tree` and the 4 and 8
+// wide trees collapsed from it.
+pub fn benchmark(name: &str, tree: &Tree<MeshTriangle>) {
+    const RAYS: u32 = 1 << 16;
+
+    let bounds = match tree.nodes.first() {
+        Some(node) => node.bounds.clone(),
+        None => return,
+    };
+
+    let rays: Vec<Ray> = (0..RAYS).map(|i| bvh::probe_ray(&bounds, i)).collect();
+
+    let measure = |primitive: &dyn Primitive| {
+        let start = Instant::now();
+        let hits = rays
+            .iter()
+            .filter(|r| primitive.intersect(r).is_some())
+            .count();
+
+        (RAYS as f64 / start.elapsed().as_secs_f64() / 1.0e6, hits)
+    };
+
+    let (binary, hits) = measure(tree);
+    let (wide4, hits4) = measure(&WideTree::<4>::new(tree));
+    let (wide8, hits8) = measure(&WideTree::<8>::new(tree));
+
+    println!(
+        "{}: binary {:.2} Mrays/s, 4 wide {:.2} Mrays/s ({:.2}x), 8 wide {:.2} Mrays/s ({:.2}x)",
+        name,
+        binary,
+        wide4,
+        wide4 / binary,
+        wide8,
+        wide8 / binary
+    );
+
+    if hits4 != hits || hits8 != hits {
+        println!(
+            "{}: hit counts differ, binary {}, 4 wide {}, 8 wide {}",
+            name, hits, hits4, hits8
+        );
+    }
+}
+
+// Unlike `f64::min` and `f64::max` these don't special case NaN, which
+// the slab test has no use for.
+fn min(a: f64, b: f64) -> f64 {
+    if a < b {
+        a
+    } else {
+        b
+    }
+}
+
+fn max(a: f64, b: f64) -> f64 {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bvh::BuildSettings;
+    use crate::primitive::{AggregatePrimitive, IndexedMesh};
+    use crate::sampler::{hash, hash_combine};
+
+    // Overlapping random triangles, so leaves hold several of them.
+    fn triangle_soup(count: u32) -> Tree<MeshTriangle> {
+        let u = |i: u32| hash(i) as f64 / 4_294_967_296.0;
+
+        let mut positions = vec![];
+
+        for i in 0..count {
+            let center = [10.0 * u(6 * i), 10.0 * u(6 * i + 1), 10.0 * u(6 * i + 2)];
+
+            for k in 0..3 {
+                let offset = |axis: u32| 2.0 * u(hash_combine(i, 3 * k + axis)) - 1.0;
+                positions.push([0, 1, 2].map(|axis| (center[axis] + offset(axis as u32)) as f32));
+            }
+        }
+
+        let mesh = Arc::new(IndexedMesh {
+            normals: vec![[0.0, 0.0, 1.0]; positions.len()],
+            uvs: vec![],
+            positions,
+            indices: (0..count).map(|i| [3 * i, 3 * i + 1, 3 * i + 2]).collect(),
+        });
+
+        let primitives = (0..count)
+            .map(|index| MeshTriangle {
+                mesh: mesh.clone(),
+                index,
+            })
+            .collect();
+
+        let settings = BuildSettings {
+            max_leaf_size: 7,
+            ..BuildSettings::default()
+        };
+
+        Tree::with_settings(AggregatePrimitive { primitives }, &settings)
+    }
+
+    fn assert_same_hits(tree: &Tree<MeshTriangle>, wide: &dyn Primitive) {
+        let bounds = tree.nodes[0].bounds.clone();
+        let mut hits = 0;
+
+        for i in 0..4096 {
+            let ray = bvh::probe_ray(&bounds, i);
+
+            match (tree.intersect(&ray), wide.intersect(&ray)) {
+                (Some(a), Some(b)) => {
+                    assert_eq!(a.t, b.t);
+                    assert_eq!(a.point, b.point);
+                    assert_eq!(a.barycentric, b.barycentric);
+                    assert_eq!(a.geometric_normal, b.geometric_normal);
+                    hits += 1;
+                }
+                (None, None) => (),
+                _ => panic!("Ray {} hits only one of the trees", i),
+            }
+
+            let t_max = 0.5 * ray.direction.norm() * (bounds.max - bounds.min).norm();
+            assert_eq!(tree.occluded(&ray, t_max), wide.occluded(&ray, t_max));
+        }
+
+        assert!(hits > 1000 && hits < 4000);
+    }
+
+    #[test]
+    fn wide_trees_hit_like_the_binary_tree() {
+        let tree = triangle_soup(1000);
+
+        assert_same_hits(&tree, &WideTree::<4>::new(&tree));
+        assert_same_hits(&tree, &WideTree::<8>::new(&tree));
+    }
+}